@@ -26,4 +26,27 @@ CREATE TABLE IF NOT EXISTS attachments (
     credentials_id TEXT NOT NULL,
     file_name TEXT,
     file_size INTEGER
-);
\ No newline at end of file
+);
+
+-- full path of every folder, e.g. Root/Team/Prod/Database
+CREATE VIEW IF NOT EXISTS folder_paths AS
+WITH RECURSIVE paths(id, path) AS (
+    SELECT id, name FROM folders WHERE parent_id NOT IN (SELECT id FROM folders)
+    UNION ALL
+    SELECT f.id, p.path || '/' || f.name FROM folders f INNER JOIN paths p ON f.parent_id = p.id
+)
+SELECT id, path FROM paths;
+
+CREATE VIRTUAL TABLE IF NOT EXISTS credentials_fts USING fts5(
+    id UNINDEXED,
+    name,
+    username,
+    notes,
+    folder_path
+);
+
+-- databases synced before the index existed
+INSERT INTO credentials_fts (id, name, username, notes, folder_path)
+SELECT c.id, c.name, c.username, c.notes, p.path FROM credentials c
+LEFT JOIN folder_paths p ON c.group_id = p.id
+WHERE NOT EXISTS (SELECT 1 FROM credentials_fts);
@@ -0,0 +1,602 @@
+//! A long running process which keeps one logged in client and answers requests over a Unix
+//! socket, so commands neither log in nor open the databases again.
+//!
+//! Requests and responses are JSON objects, one per line, e.g.
+//! `{"command":"get-password","entry_id":"94153de4-1cba-4c13-9c23-41cde415146b"}` is answered
+//! with `{"status":"password","password":"..."}`. The socket is only accessible by the user.
+//! Every connection is served by its own task, the requests themselves take turns on the client.
+
+use crate::error::Error;
+use crate::model::Credentials;
+use crate::types::Result;
+use crate::PleasantPasswordServerClient;
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::os::unix::net::UnixStream as StdUnixStream;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::{mpsc, Mutex};
+
+/// The file name of the socket in the data directory
+pub const SOCKET_NAME: &str = "agent.sock";
+
+/// How long the agents wait for the next request of a connection
+pub(crate) const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The longest idle timeout, the timers of tokio can not wait much longer than two years
+pub const MAX_IDLE_TIMEOUT: Duration = Duration::from_secs(60 * 60 * 24 * 365 * 2);
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "kebab-case")]
+pub enum AgentRequest {
+    GetPassword {
+        entry_id: String,
+    },
+    Query {
+        query: String,
+        limit: Option<u32>,
+    },
+    /// Refuses all other requests until the agent is unlocked with the password of the login
+    Lock,
+    Unlock {
+        password: String,
+        otp: Option<String>,
+    },
+    Stop,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "kebab-case")]
+pub enum AgentResponse {
+    Password { password: Option<String> },
+    Entries { entries: Vec<Credentials> },
+    Done,
+    Error { kind: ErrorKind, message: String },
+}
+
+/// The class of an error, so the caller can tell failures apart as if it made the request itself
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ErrorKind {
+    Authentication,
+    NotFound,
+    AccessDenied,
+    Server,
+    Cache,
+    Locked,
+    Other,
+}
+
+/// Fails for an idle timeout longer than [`MAX_IDLE_TIMEOUT`]
+pub fn check_idle_timeout(idle_timeout: Option<Duration>) -> Result<()> {
+    match idle_timeout {
+        Some(idle_timeout) if idle_timeout > MAX_IDLE_TIMEOUT => Err(format!(
+            "The idle timeout is too long, it can be at most {}d",
+            MAX_IDLE_TIMEOUT.as_secs() / (60 * 60 * 24)
+        )
+        .into()),
+        _ => Ok(()),
+    }
+}
+
+/// Serves requests until the idle timeout passes without a connection or a `stop` request
+pub async fn serve(
+    client: PleasantPasswordServerClient,
+    socket: &Path,
+    idle_timeout: Option<Duration>,
+) -> Result<()> {
+    check_idle_timeout(idle_timeout)?;
+    let (listener, _socket_file) = listen(socket)?;
+    info!("Agent listening on {}", socket.display());
+    // the futures of the client borrow its database, so the connections stay on this thread
+    tokio::task::LocalSet::new()
+        .run_until(accept(listener, client, idle_timeout))
+        .await
+}
+
+async fn accept(
+    mut listener: UnixListener,
+    client: PleasantPasswordServerClient,
+    idle_timeout: Option<Duration>,
+) -> Result<()> {
+    let agent = Rc::new(Mutex::new(Agent {
+        client,
+        locked: false,
+    }));
+    let (stop_sender, mut stop_receiver) = mpsc::channel(1);
+    loop {
+        let idle = async {
+            match idle_timeout {
+                Some(idle_timeout) => tokio::time::delay_for(idle_timeout).await,
+                None => futures::future::pending().await,
+            }
+        };
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = stop_receiver.recv() => {
+                info!("Stopped by request");
+                return Ok(());
+            }
+            _ = idle => {
+                info!("No request for {:?}, stopping", idle_timeout);
+                return Ok(());
+            }
+        };
+        let (stream, _) = accepted?;
+        let agent = agent.clone();
+        let mut stop_sender = stop_sender.clone();
+        tokio::task::spawn_local(async move {
+            match handle_connection(agent, stream).await {
+                Ok(true) => {
+                    let _ = stop_sender.send(()).await;
+                }
+                Ok(false) => {}
+                Err(err) => warn!("Connection failed: {}", err),
+            }
+        });
+    }
+}
+
+pub fn is_running(socket: &Path) -> bool {
+    AgentClient::connect(socket).is_some()
+}
+
+struct Agent {
+    client: PleasantPasswordServerClient,
+    locked: bool,
+}
+
+/// Answers the requests of a connection. Returns true if the agent should stop.
+async fn handle_connection(agent: Rc<Mutex<Agent>>, mut stream: UnixStream) -> Result<bool> {
+    let (reader, mut writer) = stream.split();
+    let mut lines = tokio::io::BufReader::new(reader).lines();
+    loop {
+        let line = match tokio::time::timeout(REQUEST_TIMEOUT, lines.next_line()).await {
+            Ok(line) => line?,
+            Err(_) => return Ok(false),
+        };
+        let line = match line {
+            Some(line) => line,
+            None => return Ok(false),
+        };
+        let (response, stop) = match serde_json::from_str::<AgentRequest>(line.as_str()) {
+            Ok(AgentRequest::Stop) => (AgentResponse::Done, true),
+            Ok(request) => (agent.lock().await.handle(request).await, false),
+            Err(err) => (
+                AgentResponse::Error {
+                    kind: ErrorKind::Other,
+                    message: format!("Invalid request: {}", err),
+                },
+                false,
+            ),
+        };
+        let mut response = serde_json::to_string(&response)?;
+        response.push('\n');
+        writer.write_all(response.as_bytes()).await?;
+        if stop {
+            return Ok(true);
+        }
+    }
+}
+
+impl Agent {
+    async fn handle(&mut self, request: AgentRequest) -> AgentResponse {
+        let result = match request {
+            AgentRequest::Unlock { password, otp } => self
+                .client
+                .log_in_with(password.as_str(), otp.as_deref())
+                .await
+                .map(|_| {
+                    info!("Unlocked");
+                    self.locked = false;
+                    AgentResponse::Done
+                }),
+            _ if self.locked => {
+                return AgentResponse::Error {
+                    kind: ErrorKind::Locked,
+                    message: "The agent is locked, unlock it with `agent unlock`".to_string(),
+                }
+            }
+            AgentRequest::Lock => {
+                info!("Locked");
+                self.locked = true;
+                self.client.end_session();
+                Ok(AgentResponse::Done)
+            }
+            AgentRequest::GetPassword { entry_id } => self
+                .client
+                .entry_password(entry_id.as_str())
+                .await
+                .map(|password| AgentResponse::Password { password }),
+            AgentRequest::Query { query, limit } => self
+                .client
+                .query(query.as_str(), limit)
+                .map(|entries| AgentResponse::Entries { entries }),
+            AgentRequest::Stop => Ok(AgentResponse::Done),
+        };
+        result.unwrap_or_else(error_response)
+    }
+}
+
+/// Keeps the message without the prefix of the class, the client adds it again
+fn error_response(err: Error) -> AgentResponse {
+    let (kind, message) = match err {
+        Error::Authentication(message) => (ErrorKind::Authentication, message),
+        Error::NotFound(message) => (ErrorKind::NotFound, message),
+        Error::AccessDenied(message) => (ErrorKind::AccessDenied, message),
+        Error::Server(message) => (ErrorKind::Server, message),
+        Error::Network(err) => (
+            ErrorKind::Server,
+            format!("The agent could not reach the server: {}", err),
+        ),
+        Error::Cache(message) => (ErrorKind::Cache, message),
+        err => (ErrorKind::Other, err.to_string()),
+    };
+    AgentResponse::Error { kind, message }
+}
+
+/// Binds a socket only accessible by the user. The socket is removed when the guard is dropped.
+pub(crate) fn listen(socket: &Path) -> Result<(UnixListener, SocketFile)> {
+    if StdUnixStream::connect(socket).is_ok() {
+        return Err(format!("An agent is already running on {}", socket.display()).into());
+    }
+    // left over by an agent which was killed, anything else at the path is kept
+    match std::fs::symlink_metadata(socket) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(socket)?,
+        Ok(_) => {
+            return Err(format!("{} exists and is not a socket", socket.display()).into());
+        }
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err.into()),
+    }
+    // the socket is bound in a directory only the user can enter and moved into place once its
+    // permissions are tightened, so nobody can connect while it has those of the umask
+    let private_dir = private_dir(socket)?;
+    let bound = private_dir.join("socket");
+    let listener = UnixListener::bind(&bound)
+        .map_err(Error::from)
+        .and_then(|listener| {
+            std::fs::set_permissions(&bound, std::fs::Permissions::from_mode(0o600))?;
+            std::fs::rename(&bound, socket)?;
+            Ok(listener)
+        });
+    let _ = std::fs::remove_file(&bound);
+    let _ = std::fs::remove_dir(&private_dir);
+    Ok((listener?, SocketFile(socket.to_path_buf())))
+}
+
+/// Creates an empty directory next to the socket which only the user can access
+fn private_dir(socket: &Path) -> Result<PathBuf> {
+    let name = socket
+        .file_name()
+        .ok_or_else(|| format!("{} is not a valid socket path", socket.display()))?;
+    let mut dir_name = std::ffi::OsString::from(".");
+    dir_name.push(name);
+    dir_name.push(format!(".{}", std::process::id()));
+    let dir = socket.with_file_name(dir_name);
+    std::fs::DirBuilder::new().mode(0o700).create(&dir)?;
+    Ok(dir)
+}
+
+/// Removes the socket when the agent stops
+pub(crate) struct SocketFile(PathBuf);
+
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// A connection to a running agent
+pub struct AgentClient {
+    stream: BufReader<StdUnixStream>,
+}
+
+impl AgentClient {
+    /// Connects to the agent, `None` if there is none
+    pub fn connect(socket: &Path) -> Option<AgentClient> {
+        let stream = StdUnixStream::connect(socket).ok()?;
+        debug!("Connected to the agent on {}", socket.display());
+        Some(AgentClient {
+            stream: BufReader::new(stream),
+        })
+    }
+
+    /// Sends a request. Error responses become errors, with the same class as on the agent.
+    pub fn request(&mut self, request: &AgentRequest) -> Result<AgentResponse> {
+        let mut line = serde_json::to_string(request)?;
+        line.push('\n');
+        self.stream.get_mut().write_all(line.as_bytes())?;
+
+        let mut line = String::new();
+        if self.stream.read_line(&mut line)? == 0 {
+            return Err("The agent closed the connection".into());
+        }
+        match serde_json::from_str(line.as_str())? {
+            AgentResponse::Error { kind, message } => Err(match kind {
+                ErrorKind::Authentication | ErrorKind::Locked => Error::Authentication(message),
+                ErrorKind::NotFound => Error::NotFound(message),
+                ErrorKind::AccessDenied => Error::AccessDenied(message),
+                ErrorKind::Server => Error::Server(message),
+                ErrorKind::Cache => Error::Cache(message),
+                ErrorKind::Other => Error::Other(message),
+            }),
+            response => Ok(response),
+        }
+    }
+
+    pub fn entry_password(&mut self, entry_id: &str) -> Result<Option<String>> {
+        match self.request(&AgentRequest::GetPassword {
+            entry_id: entry_id.to_string(),
+        })? {
+            AgentResponse::Password { password } => Ok(password),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    pub fn query(&mut self, query: &str, limit: Option<u32>) -> Result<Vec<Credentials>> {
+        match self.request(&AgentRequest::Query {
+            query: query.to_string(),
+            limit,
+        })? {
+            AgentResponse::Entries { entries } => Ok(entries),
+            response => Err(unexpected(response)),
+        }
+    }
+}
+
+fn unexpected(response: AgentResponse) -> Error {
+    Error::Other(format!("Unexpected answer of the agent: {:?}", response))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache_policy::CachePolicy;
+    use crate::{Account, CacheKey, OtpSource, PasswordSource};
+    use tokio::io::AsyncReadExt;
+    use url::Url;
+
+    /// A data directory which is removed when the test is done
+    struct TestDir(PathBuf);
+
+    impl TestDir {
+        fn new(name: &str) -> TestDir {
+            let dir = std::env::temp_dir().join(format!(
+                "pleasant-agent-{}-{}",
+                name,
+                std::process::id()
+            ));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            TestDir(dir)
+        }
+
+        /// An agent whose client reaches no server, it only knows the local database
+        fn agent(&self) -> Rc<Mutex<Agent>> {
+            let account = Account {
+                login: "jdoe".to_string(),
+                password: PasswordSource::Value("s3cret".to_string().into()),
+                otp: OtpSource::Prompt,
+            };
+            let client = PleasantPasswordServerClient::new(
+                Url::parse("http://127.0.0.1:1/").unwrap(),
+                reqwest::Client::new(),
+                account,
+                self.0.clone(),
+                CacheKey::KeyFile(self.0.join("cache.key")),
+                CachePolicy::default(),
+            )
+            .unwrap();
+            Rc::new(Mutex::new(Agent {
+                client,
+                locked: false,
+            }))
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// Sends the lines over one connection. Returns the responses and whether the agent should
+    /// stop.
+    async fn exchange(agent: &Rc<Mutex<Agent>>, requests: &[&str]) -> (Vec<AgentResponse>, bool) {
+        let (stream, mut peer) = UnixStream::pair().unwrap();
+        for request in requests.iter() {
+            peer.write_all(format!("{}\n", request).as_bytes())
+                .await
+                .unwrap();
+        }
+        peer.shutdown(std::net::Shutdown::Write).unwrap();
+        let stop = handle_connection(agent.clone(), stream).await.unwrap();
+        let mut output = String::new();
+        peer.read_to_string(&mut output).await.unwrap();
+        let responses = output
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        (responses, stop)
+    }
+
+    fn request(request: AgentRequest) -> String {
+        serde_json::to_string(&request).unwrap()
+    }
+
+    fn query() -> String {
+        request(AgentRequest::Query {
+            query: "prod".to_string(),
+            limit: None,
+        })
+    }
+
+    #[tokio::test]
+    async fn answers_one_request_per_line() {
+        let dir = TestDir::new("protocol");
+        let agent = dir.agent();
+
+        let (responses, stop) =
+            exchange(&agent, &[query().as_str(), "{\"command\":\"sing\"}"]).await;
+        assert!(!stop);
+        assert!(matches!(
+            responses.as_slice(),
+            [
+                AgentResponse::Entries { entries },
+                AgentResponse::Error { kind: ErrorKind::Other, message },
+            ] if entries.is_empty() && message.starts_with("Invalid request")
+        ));
+
+        // the rest of the connection is not read after a stop
+        let stop_request = request(AgentRequest::Stop);
+        let (responses, stop) = exchange(&agent, &[stop_request.as_str(), query().as_str()]).await;
+        assert!(stop);
+        assert!(matches!(responses.as_slice(), [AgentResponse::Done]));
+    }
+
+    #[tokio::test]
+    async fn refuses_requests_while_locked() {
+        let dir = TestDir::new("lock");
+        let agent = dir.agent();
+        let get_password = request(AgentRequest::GetPassword {
+            entry_id: "94153de4-1cba-4c13-9c23-41cde415146b".to_string(),
+        });
+        let unlock = request(AgentRequest::Unlock {
+            password: "s3cret".to_string(),
+            otp: None,
+        });
+
+        let lock = request(AgentRequest::Lock);
+        let (responses, _) = exchange(
+            &agent,
+            &[
+                lock.as_str(),
+                query().as_str(),
+                get_password.as_str(),
+                lock.as_str(),
+            ],
+        )
+        .await;
+        assert!(matches!(
+            responses.as_slice(),
+            [
+                AgentResponse::Done,
+                AgentResponse::Error {
+                    kind: ErrorKind::Locked,
+                    ..
+                },
+                AgentResponse::Error {
+                    kind: ErrorKind::Locked,
+                    ..
+                },
+                AgentResponse::Error {
+                    kind: ErrorKind::Locked,
+                    ..
+                },
+            ]
+        ));
+
+        // the password is checked by the server, which can not be reached
+        let (responses, _) = exchange(&agent, &[unlock.as_str(), query().as_str()]).await;
+        assert!(matches!(
+            responses.as_slice(),
+            [
+                AgentResponse::Error {
+                    kind: ErrorKind::Server,
+                    ..
+                },
+                AgentResponse::Error {
+                    kind: ErrorKind::Locked,
+                    ..
+                },
+            ]
+        ));
+        assert!(agent.lock().await.locked);
+    }
+
+    #[tokio::test]
+    async fn stops_when_idle() {
+        let dir = TestDir::new("idle");
+        let socket = dir.0.join(SOCKET_NAME);
+        let client = Rc::try_unwrap(dir.agent())
+            .ok()
+            .unwrap()
+            .into_inner()
+            .client;
+
+        let served = tokio::time::timeout(
+            Duration::from_secs(10),
+            serve(client, &socket, Some(Duration::from_millis(50))),
+        )
+        .await;
+        assert!(matches!(served, Ok(Ok(()))));
+        assert!(!socket.exists());
+    }
+
+    #[tokio::test]
+    async fn rejects_idle_timeouts_beyond_the_timers() {
+        let dir = TestDir::new("max-idle");
+        let socket = dir.0.join(SOCKET_NAME);
+        let client = Rc::try_unwrap(dir.agent())
+            .ok()
+            .unwrap()
+            .into_inner()
+            .client;
+
+        assert!(check_idle_timeout(Some(MAX_IDLE_TIMEOUT)).is_ok());
+        let err = serve(
+            client,
+            &socket,
+            Some(MAX_IDLE_TIMEOUT + Duration::from_secs(1)),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "The idle timeout is too long, it can be at most 730d"
+        );
+        assert!(!socket.exists());
+    }
+
+    /// The error the client makes of the response of the agent
+    fn received(response: AgentResponse) -> Error {
+        let (agent, peer) = StdUnixStream::pair().unwrap();
+        let mut line = serde_json::to_string(&response).unwrap();
+        line.push('\n');
+        (&agent).write_all(line.as_bytes()).unwrap();
+        let mut client = AgentClient {
+            stream: BufReader::new(peer),
+        };
+        client.request(&AgentRequest::Lock).unwrap_err()
+    }
+
+    #[test]
+    fn keeps_the_kind_of_errors() {
+        let errors = vec![
+            Error::Authentication("wrong password".to_string()),
+            Error::NotFound("no entry".to_string()),
+            Error::AccessDenied("no permission".to_string()),
+            Error::Server("HTTP 500".to_string()),
+            Error::Cache("wrong key".to_string()),
+            Error::Other("broken".to_string()),
+        ];
+        for err in errors {
+            let expected = err.to_string();
+            assert_eq!(received(error_response(err)).to_string(), expected);
+        }
+
+        let locked = AgentResponse::Error {
+            kind: ErrorKind::Locked,
+            message: "The agent is locked".to_string(),
+        };
+        assert!(
+            matches!(received(locked), Error::Authentication(message) if message == "The agent is locked")
+        );
+    }
+}
@@ -1,4 +1,6 @@
 use crate::types::Result;
+use std::fs::OpenOptions;
+use std::io::Write;
 use std::path::{Path, PathBuf};
 
 pub fn app_file<S: AsRef<str>, P: AsRef<Path>>(app_name: S, file_name: P) -> Result<PathBuf> {
@@ -9,3 +11,35 @@ pub fn app_file<S: AsRef<str>, P: AsRef<Path>>(app_name: S, file_name: P) -> Res
     std::fs::create_dir_all(&app_dir)?;
     Ok(app_dir.join(file_name))
 }
+
+/// The directory for the cache and the local database of a profile. Without a profile it is the
+/// app directory itself, where they were kept before profiles existed.
+pub fn data_dir(profile: Option<&str>) -> Result<PathBuf> {
+    let app_dir = app_file("pleasant_password_client", "")?;
+    let data_dir = match profile {
+        Some(profile) => app_dir.join("profiles").join(profile),
+        None => app_dir,
+    };
+    std::fs::create_dir_all(&data_dir)?;
+    Ok(data_dir)
+}
+
+/// Writes a file which is only readable by the current user
+pub fn write_private_file<P: AsRef<Path>>(path: P, contents: &[u8]) -> Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path)?;
+    #[cfg(unix)]
+    {
+        // the mode only applies to new files
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+    }
+    file.write_all(contents)?;
+    Ok(())
+}
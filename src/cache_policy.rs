@@ -0,0 +1,256 @@
+//! Which passwords are cached and for how long.
+//!
+//! A policy has a default lifetime for passwords and a list of rules like
+//! `folder:Root/Prod/*=never` or `entry:*admin*=1h`. Folder rules match the full folder path,
+//! entry rules the name or id of the entry. The first matching rule wins. Patterns support `*`
+//! (any characters) and `?` (a single character) and are case insensitive.
+
+use std::str::FromStr;
+
+/// Lifetime of cached passwords if nothing else is configured
+pub const DEFAULT_PASSWORD_TTL: i64 = 60 * 60 * 24;
+
+/// The longest lifetime, 100 years. Expiry dates further out can not be stored.
+pub const MAX_TTL: i64 = 60 * 60 * 24 * 365 * 100;
+
+#[derive(Debug, Clone)]
+pub struct CachePolicy {
+    /// Lifetime of cached passwords in seconds, `None` never caches passwords
+    pub password_ttl: Option<i64>,
+    pub rules: Vec<CacheRule>,
+    /// Neither reads from nor writes to the cache, not even the access token
+    pub bypass: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CacheRule {
+    pub target: RuleTarget,
+    pub pattern: String,
+    /// Lifetime in seconds, `None` never caches matching passwords
+    pub ttl: Option<i64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RuleTarget {
+    Folder,
+    Entry,
+}
+
+impl Default for CachePolicy {
+    fn default() -> Self {
+        CachePolicy {
+            password_ttl: Some(DEFAULT_PASSWORD_TTL),
+            rules: Vec::new(),
+            bypass: false,
+        }
+    }
+}
+
+impl CachePolicy {
+    /// Parses a `;` separated list of rules
+    pub fn parse_rules(rules: &str) -> Result<Vec<CacheRule>, String> {
+        rules
+            .split(';')
+            .map(str::trim)
+            .filter(|rule| !rule.is_empty())
+            .map(str::parse)
+            .collect()
+    }
+
+    /// The lifetime for a password of an entry.
+    ///
+    /// `folder_path` and `name` are `None` if the entry is not in the local database.
+    pub fn password_ttl(
+        &self,
+        entry_id: &str,
+        folder_path: Option<&str>,
+        name: Option<&str>,
+    ) -> Option<i64> {
+        if self.bypass {
+            return None;
+        }
+        for rule in self.rules.iter() {
+            let matches = match rule.target {
+                RuleTarget::Folder => folder_path
+                    .map(|path| wildcard_match(rule.pattern.as_str(), path))
+                    .unwrap_or(false),
+                RuleTarget::Entry => {
+                    wildcard_match(rule.pattern.as_str(), entry_id)
+                        || name
+                            .map(|name| wildcard_match(rule.pattern.as_str(), name))
+                            .unwrap_or(false)
+                }
+            };
+            if matches {
+                return rule.ttl;
+            }
+        }
+        self.password_ttl
+    }
+
+    /// Whether the rules need the folder path and name of an entry
+    pub fn has_rules(&self) -> bool {
+        !self.rules.is_empty()
+    }
+}
+
+impl FromStr for CacheRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "Invalid cache rule '{}', expected e.g. folder:Root/Prod/*=never or entry:*admin*=1h",
+                s
+            )
+        };
+        let (selector, ttl) = match s.rfind('=') {
+            Some(index) => (&s[..index], &s[index + 1..]),
+            None => return Err(invalid()),
+        };
+        let (target, pattern) = match selector.find(':') {
+            Some(index) => (&selector[..index], &selector[index + 1..]),
+            None => return Err(invalid()),
+        };
+        let target = match target.trim().to_lowercase().as_str() {
+            "folder" => RuleTarget::Folder,
+            "entry" => RuleTarget::Entry,
+            _ => return Err(invalid()),
+        };
+        Ok(CacheRule {
+            target,
+            pattern: pattern.trim().to_string(),
+            ttl: parse_ttl(ttl)?,
+        })
+    }
+}
+
+/// Parses a lifetime like `90s`, `30m`, `12h`, `7d` or a plain number of seconds.
+///
+/// `never` and `0` return `None`. Lifetimes beyond [`MAX_TTL`] are rejected.
+pub fn parse_ttl(value: &str) -> Result<Option<i64>, String> {
+    let value = value.trim();
+    if value.eq_ignore_ascii_case("never") {
+        return Ok(None);
+    }
+    let invalid = || {
+        format!(
+            "Invalid cache lifetime '{}', expected never or a duration like 90s, 30m, 12h or 7d",
+            value
+        )
+    };
+    let (amount, factor) = match value.chars().last() {
+        Some('s') => (&value[..value.len() - 1], 1),
+        Some('m') => (&value[..value.len() - 1], 60),
+        Some('h') => (&value[..value.len() - 1], 60 * 60),
+        Some('d') => (&value[..value.len() - 1], 60 * 60 * 24),
+        _ => (value, 1),
+    };
+    let amount: i64 = amount.parse().map_err(|_| invalid())?;
+    match amount.checked_mul(factor) {
+        Some(0) => Ok(None),
+        Some(ttl) if ttl > 0 && ttl <= MAX_TTL => Ok(Some(ttl)),
+        Some(ttl) if ttl > 0 => Err(format!(
+            "Cache lifetime '{}' is too long, it can be at most {}d",
+            value,
+            MAX_TTL / (60 * 60 * 24)
+        )),
+        _ => Err(invalid()),
+    }
+}
+
+/// Case insensitive match of `*` and `?` wildcards against the whole value
+fn wildcard_match(pattern: &str, value: &str) -> bool {
+    let pattern: Vec<char> = pattern.to_lowercase().chars().collect();
+    let value: Vec<char> = value.to_lowercase().chars().collect();
+
+    // backtracking to the last star is enough as long as there are no character classes
+    let (mut p, mut v) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while v < value.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, v));
+                p += 1;
+            }
+            Some(c) if *c == '?' || *c == value[v] => {
+                p += 1;
+                v += 1;
+            }
+            _ => match star {
+                Some((star_p, star_v)) => {
+                    p = star_p + 1;
+                    v = star_v + 1;
+                    star = Some((star_p, star_v + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_wildcards() {
+        assert!(wildcard_match("Root/Prod/*", "Root/Prod/DB"));
+        assert!(wildcard_match("root/prod/*", "ROOT/PROD/DB"));
+        assert!(wildcard_match("*admin*", "db-admin-prod"));
+        assert!(wildcard_match("*", ""));
+        assert!(wildcard_match("a?c", "abc"));
+        assert!(wildcard_match("a*b*c", "aXbYbZc"));
+        assert!(wildcard_match("*.pem", "key.pem.pem"));
+        assert!(wildcard_match("ä*", "Äpfel"));
+
+        assert!(!wildcard_match("Root/Prod/*", "Root/Production"));
+        assert!(!wildcard_match("Root/Prod", "Root/Prod/DB"));
+        assert!(!wildcard_match("a?c", "ac"));
+        assert!(!wildcard_match("*admin", "admin-prod"));
+        assert!(!wildcard_match("", "a"));
+    }
+
+    #[test]
+    fn applies_the_first_matching_rule() {
+        let policy = CachePolicy {
+            rules: CachePolicy::parse_rules("folder:Root/Prod/*=never; entry:*admin*=1h").unwrap(),
+            ..CachePolicy::default()
+        };
+        assert_eq!(
+            policy.password_ttl("id", Some("Root/Prod/DB"), Some("admin")),
+            None
+        );
+        assert_eq!(
+            policy.password_ttl("id", Some("Root/Test"), Some("db admin")),
+            Some(3600)
+        );
+        assert_eq!(
+            policy.password_ttl("id", None, None),
+            Some(DEFAULT_PASSWORD_TTL)
+        );
+        let bypass = CachePolicy {
+            bypass: true,
+            ..policy
+        };
+        assert_eq!(bypass.password_ttl("id", None, None), None);
+    }
+
+    #[test]
+    fn parses_lifetimes() {
+        assert_eq!(parse_ttl("90"), Ok(Some(90)));
+        assert_eq!(parse_ttl("30m"), Ok(Some(1800)));
+        assert_eq!(parse_ttl(" 7d "), Ok(Some(7 * 24 * 3600)));
+        assert_eq!(parse_ttl("never"), Ok(None));
+        assert_eq!(parse_ttl("0s"), Ok(None));
+        assert!(parse_ttl("-1h").is_err());
+        assert!(parse_ttl("1w").is_err());
+        assert!(parse_ttl("999999999999999999d").is_err());
+        assert_eq!(parse_ttl("36500d"), Ok(Some(MAX_TTL)));
+        assert_eq!(
+            parse_ttl("999999999d"),
+            Err("Cache lifetime '999999999d' is too long, it can be at most 36500d".to_string())
+        );
+    }
+}
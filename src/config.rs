@@ -0,0 +1,121 @@
+//! The config file with named profiles, `~/.pleasant_password_client/config.toml` by default.
+//!
+//! ```toml
+//! default_profile = "staging"
+//!
+//! [profiles.staging]
+//! url = "https://pleasant-staging.example.com"
+//! login = "jdoe"
+//! cache_ttl = "1h"
+//!
+//! [profiles.production]
+//! url = "https://pleasant.example.com"
+//! login = "jdoe"
+//! password_command = "pass show pleasant/production"
+//! cache_rules = ["folder:Root/Prod/*=never"]
+//! output = "json"
+//! tls_ca_bundle = "/etc/ssl/corporate-ca.pem"
+//! tls_pinned_keys = ["sha256/47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU="]
+//! ```
+//!
+//! Settings from the environment take precedence over the profile.
+
+use crate::types::Result;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    /// used if neither `--profile` nor `PLEASANT_PASSWORD_PROFILE` is given
+    pub default_profile: Option<String>,
+    #[serde(default)]
+    pub profiles: HashMap<String, Profile>,
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    pub url: Option<String>,
+    pub login: Option<String>,
+    pub password_command: Option<String>,
+    pub http_proxy: Option<String>,
+    pub https_proxy: Option<String>,
+    pub cache_key_file: Option<String>,
+    pub cache_ttl: Option<String>,
+    #[serde(default)]
+    pub cache_rules: Vec<String>,
+    pub output: Option<String>,
+    pub tls_ca_bundle: Option<String>,
+    pub tls_client_cert: Option<String>,
+    pub tls_client_key: Option<String>,
+    pub tls_min_version: Option<String>,
+    #[serde(default)]
+    pub tls_pinned_keys: Vec<String>,
+    /// query templates of the `git-credential` subcommand
+    #[serde(default)]
+    pub git_credential_lookup: Vec<String>,
+    /// query templates of the `docker-credential` subcommand
+    #[serde(default)]
+    pub docker_credential_lookup: Vec<String>,
+    /// full path of the folder for entries created by `docker login`
+    pub docker_folder: Option<String>,
+    /// query for the entries holding the keys of `ssh-agent`
+    pub ssh_query: Option<String>,
+    /// full path of the folder with the entries holding the keys of `ssh-agent`
+    pub ssh_folder: Option<String>,
+}
+
+impl ConfigFile {
+    /// Loads the config file. A missing file is an empty config.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<ConfigFile> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Ok(ConfigFile::default());
+        }
+        let content = std::fs::read_to_string(path)?;
+        toml::from_str(content.as_str())
+            .map_err(|err| format!("Invalid config file {}: {}", path.display(), err).into())
+    }
+
+    /// The profile of the given name or the default profile, together with its name
+    pub fn profile(&self, name: Option<&str>) -> Result<Option<(String, Profile)>> {
+        let name = match name.or(self.default_profile.as_deref()) {
+            Some(name) => name,
+            None => return Ok(None),
+        };
+        if !is_profile_name(name) {
+            return Err(format!(
+                "Invalid profile name '{}', only letters, digits, - and _ are allowed",
+                name
+            )
+            .into());
+        }
+        match self.profiles.get(name) {
+            Some(profile) => Ok(Some((name.to_string(), profile.clone()))),
+            None => {
+                let mut known: Vec<&str> = self.profiles.keys().map(String::as_str).collect();
+                known.sort_unstable();
+                Err(format!(
+                    "Unknown profile '{}', the config file has {}",
+                    name,
+                    if known.is_empty() {
+                        "no profiles".to_string()
+                    } else {
+                        known.join(", ")
+                    }
+                )
+                .into())
+            }
+        }
+    }
+}
+
+/// Profile names become directory names
+fn is_profile_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
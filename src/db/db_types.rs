@@ -1,8 +1,8 @@
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 type DateTime = chrono::DateTime<chrono::Utc>;
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "PascalCase")]
 pub struct CredentialEntry {
     pub id: String,
@@ -17,10 +17,12 @@ pub struct CredentialEntry {
     pub synced: Option<DateTime>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "PascalCase")]
 pub struct Folder {
+    #[serde(default)]
     pub credentials: Vec<CredentialEntry>,
+    #[serde(default)]
     pub children: Vec<Folder>,
     pub id: String,
     pub name: String,
@@ -31,7 +33,7 @@ pub struct Folder {
     pub synced: Option<DateTime>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "PascalCase")]
 pub struct Attachment {
     pub credential_object_id: String,
@@ -39,3 +41,65 @@ pub struct Attachment {
     pub file_name: String,
     pub file_size: i64,
 }
+
+/// A credential entry which is not yet known to the server
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct NewCredentialEntry {
+    pub name: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub notes: Option<String>,
+    pub group_id: String,
+}
+
+/// Partial update of a credential entry. Only present fields are sent to the server.
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct CredentialEntryChanges {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub notes: Option<String>,
+}
+
+/// A folder which is not yet known to the server
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct NewFolder {
+    pub name: String,
+    pub parent_id: String,
+}
+
+/// Partial update of a folder. Only present fields are sent to the server.
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct FolderChanges {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent_id: Option<String>,
+}
+
+/// An attachment including its content, as returned when downloading it
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct AttachmentData {
+    pub file_name: String,
+    /// base64 encoded file content
+    pub file_data: String,
+}
+
+/// An attachment which is not yet known to the server
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct NewAttachment {
+    pub credential_object_id: String,
+    pub file_name: String,
+    /// base64 encoded file content
+    pub file_data: String,
+}
@@ -0,0 +1,169 @@
+//! The Docker credential helper protocol, see
+//! <https://github.com/docker/docker-credential-helpers>.
+//!
+//! Docker runs `docker-credential-<name> <operation>`. `get` and `erase` read the server url
+//! from stdin, `store` reads the credentials as JSON and `list` prints a JSON object of server
+//! urls and usernames. Errors are printed to stdout, where Docker looks for them.
+//!
+//! Server urls are looked up with the query templates of [`crate::git_credential`], with
+//! `{protocol}`, `{host}` and `{path}` taken from the url. Like for git, only an entry named after
+//! the host of the registry is used.
+
+use crate::db::db_types::{CredentialEntryChanges, NewCredentialEntry};
+use crate::git_credential::{self, CredentialLookup, CredentialRequest};
+use crate::model::Credentials;
+use crate::{Error, PleasantPasswordServerClient};
+use serde::{Deserialize, Serialize};
+use std::io::Read;
+use url::Url;
+
+/// The name of the binary Docker runs for `"credsStore": "pleasant"`
+pub const HELPER_NAME: &str = "docker-credential-pleasant";
+
+/// Docker treats this answer to `get` as "no credentials", e.g. to pull public images
+pub const CREDENTIALS_NOT_FOUND: &str = "credentials not found in native keychain";
+
+/// Used if no lookup is configured
+pub const DEFAULT_LOOKUP: &str = "entry:{host}";
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct DockerCredentials {
+    #[serde(rename = "ServerURL")]
+    pub server_url: String,
+    pub username: String,
+    pub secret: String,
+}
+
+/// Reads the server url which `get` and `erase` receive on stdin
+pub fn read_server_url<R: Read>(mut input: R) -> crate::Result<String> {
+    let mut server_url = String::new();
+    input.read_to_string(&mut server_url)?;
+    let server_url = server_url.trim();
+    if server_url.is_empty() {
+        return Err("Docker sent no server url".into());
+    }
+    Ok(server_url.to_string())
+}
+
+/// Whether an entry name is a server url, as Docker passes them to `store`, e.g.
+/// `https://index.docker.io/v1/` or `registry.example.com:5000`. Like Docker, a registry without
+/// a scheme needs a `.` or a port in its host or has to be `localhost`.
+pub fn is_server_url(name: &str) -> bool {
+    let url = if name.contains("://") {
+        Url::parse(name)
+    } else {
+        Url::parse(format!("https://{}", name).as_str())
+    };
+    match url {
+        Ok(url) => match url.host_str() {
+            Some(host) => {
+                name.contains("://")
+                    || host.contains('.')
+                    || host == "localhost"
+                    || url.port().is_some()
+            }
+            None => false,
+        },
+        Err(_) => false,
+    }
+}
+
+/// Reads the credentials which `store` receives on stdin
+pub fn read_credentials<R: Read>(input: R) -> crate::Result<DockerCredentials> {
+    Ok(serde_json::from_reader(input)?)
+}
+
+/// The entry named after the registry of a server url, see [`git_credential::find_entry`]
+pub fn find_entry(
+    client: &PleasantPasswordServerClient,
+    lookup: &CredentialLookup,
+    server_url: &str,
+) -> crate::Result<Option<Credentials>> {
+    git_credential::find_entry(client, lookup, &CredentialRequest::from_url(server_url))
+}
+
+/// Answers `get`. `None` means Docker should go on without credentials.
+pub async fn get(
+    client: &PleasantPasswordServerClient,
+    lookup: &CredentialLookup,
+    server_url: String,
+) -> crate::Result<Option<DockerCredentials>> {
+    let entry = match find_entry(client, lookup, server_url.as_str())? {
+        Some(entry) => entry,
+        None => return Ok(None),
+    };
+    let secret = client
+        .entry_password(entry.id.as_str())
+        .await?
+        .ok_or_else(|| Error::NotFound(format!("Entry {} has no password", entry.id)))?;
+    Ok(Some(DockerCredentials {
+        server_url,
+        username: entry.username.unwrap_or_default(),
+        secret,
+    }))
+}
+
+/// Answers `store`. Updates the entry named after the registry or creates one, named after the
+/// host, in `folder`.
+pub async fn store(
+    client: &PleasantPasswordServerClient,
+    lookup: &CredentialLookup,
+    folder: Option<&str>,
+    credentials: DockerCredentials,
+) -> crate::Result<()> {
+    if let Some(entry) = find_entry(client, lookup, credentials.server_url.as_str())? {
+        let changes = CredentialEntryChanges {
+            username: Some(credentials.username),
+            password: Some(credentials.secret),
+            ..CredentialEntryChanges::default()
+        };
+        return client.update_entry(entry.id.as_str(), changes).await;
+    }
+
+    let folder = folder.ok_or(
+        "No entry matches the server and PLEASANT_PASSWORD_DOCKER_FOLDER is not set, so there is no folder for a new one",
+    )?;
+    let request = CredentialRequest::from_url(credentials.server_url.as_str());
+    let host = request
+        .get("host")
+        .ok_or_else(|| format!("The server url {} has no host", credentials.server_url))?;
+    let entry = NewCredentialEntry {
+        name: host.to_string(),
+        username: Some(credentials.username),
+        password: Some(credentials.secret),
+        notes: None,
+        group_id: client.folder_id(folder)?,
+    };
+    client.create_entry(entry).await?;
+    Ok(())
+}
+
+/// Answers `erase`, run by `docker logout`. Only the cached password of the entry named after
+/// the registry is forgotten, the entry stays in Pleasant.
+pub fn erase(
+    client: &PleasantPasswordServerClient,
+    lookup: &CredentialLookup,
+    server_url: &str,
+) -> crate::Result<()> {
+    if let Some(entry) = find_entry(client, lookup, server_url)? {
+        client.forget_password(entry.id.as_str())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recognizes_server_urls() {
+        assert!(is_server_url("https://index.docker.io/v1/"));
+        assert!(is_server_url("registry.example.com"));
+        assert!(is_server_url("registry:5000"));
+        assert!(is_server_url("localhost"));
+        assert!(!is_server_url("Prod DB"));
+        assert!(!is_server_url("deploy"));
+        assert!(!is_server_url(""));
+    }
+}
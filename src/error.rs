@@ -0,0 +1,136 @@
+//! The error type of the client. Its variants let callers, e.g. `main`, tell failures apart.
+
+use crate::query::QueryError;
+use crate::settings::SettingErrors;
+use crate::template::TemplateError;
+use crate::timed_cache::CacheError;
+use reqwest::StatusCode;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+
+#[derive(Debug)]
+pub enum Error {
+    /// The login, password or one time password was rejected
+    Authentication(String),
+    /// An entry, folder or attachment does not exist or no entry matches a query
+    NotFound(String),
+    /// The user has no permission for an entry or folder
+    AccessDenied(String),
+    /// The server could not be reached
+    Network(reqwest::Error),
+    /// The server answered with an error or something unexpected
+    Server(String),
+    Cache(String),
+    /// The local database of synced entries
+    Database(rusqlite::Error),
+    Settings(SettingErrors),
+    Io(std::io::Error),
+    Other(String),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Error::Authentication(message) => write!(f, "Authentication failed: {}", message),
+            Error::NotFound(message) => write!(f, "{}", message),
+            Error::AccessDenied(message) => write!(f, "Access denied: {}", message),
+            Error::Network(err) => write!(f, "Could not reach the server: {}", err),
+            Error::Server(message) => write!(f, "Server error: {}", message),
+            Error::Cache(message) => write!(f, "Cache error: {}", message),
+            Error::Database(err) => write!(f, "Local database error: {}", err),
+            Error::Settings(errors) => write!(f, "{}", errors),
+            Error::Io(err) => write!(f, "{}", err),
+            Error::Other(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Network(err) => Some(err),
+            Error::Database(err) => Some(err),
+            Error::Settings(errors) => Some(errors),
+            Error::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// Sorts failed requests by their status, e.g. after `Response::error_for_status`
+impl From<reqwest::Error> for Error {
+    fn from(err: reqwest::Error) -> Self {
+        match err.status() {
+            Some(StatusCode::UNAUTHORIZED) => Error::Authentication(err.to_string()),
+            Some(StatusCode::FORBIDDEN) => Error::AccessDenied(err.to_string()),
+            Some(StatusCode::NOT_FOUND) => Error::NotFound(err.to_string()),
+            Some(_) => Error::Server(err.to_string()),
+            None if err.is_decode() => Error::Server(format!("Unexpected response: {}", err)),
+            None => Error::Network(err),
+        }
+    }
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(err: rusqlite::Error) -> Self {
+        Error::Database(err)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+impl From<CacheError> for Error {
+    fn from(err: CacheError) -> Self {
+        Error::Cache(err.to_string())
+    }
+}
+
+impl From<SettingErrors> for Error {
+    fn from(errors: SettingErrors) -> Self {
+        Error::Settings(errors)
+    }
+}
+
+/// Attachments are base64 encoded by the server
+impl From<base64::DecodeError> for Error {
+    fn from(err: base64::DecodeError) -> Self {
+        Error::Server(format!("Invalid base64 data: {}", err))
+    }
+}
+
+impl From<String> for Error {
+    fn from(message: String) -> Self {
+        Error::Other(message)
+    }
+}
+
+impl From<&str> for Error {
+    fn from(message: &str) -> Self {
+        Error::Other(message.to_string())
+    }
+}
+
+macro_rules! other_error_from {
+    ($($error:ty),*) => {
+        $(
+            impl From<$error> for Error {
+                fn from(err: $error) -> Self {
+                    Error::Other(err.to_string())
+                }
+            }
+        )*
+    };
+}
+
+other_error_from!(
+    QueryError,
+    TemplateError,
+    serde_json::Error,
+    serde_yaml::Error,
+    csv::Error,
+    std::time::SystemTimeError,
+    hmac::digest::InvalidLength
+);
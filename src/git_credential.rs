@@ -0,0 +1,354 @@
+//! Git's credential helper protocol, see `git help credential`.
+//!
+//! Git writes `key=value` lines like `protocol=https`, `host=gitlab.example.com` and
+//! `path=group/project.git` to stdin. The entry is found with a list of query templates like
+//! `path:Root/Git entry:{host} user:{username}`, which are tried in order. Placeholders are
+//! `{protocol}`, `{host}`, `{path}` and `{username}`. Terms with a placeholder git did not send
+//! are left out, so `entry:{host} user:{username}` also works without a username.
+//!
+//! Whatever the templates match, only an entry named after the host is used, see
+//! [`CredentialRequest::names`].
+
+use crate::model::Credentials;
+use crate::query::quote;
+use crate::PleasantPasswordServerClient;
+use log::*;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::io::{BufRead, Write};
+
+/// Used if no lookup is configured, `;` separated
+pub const DEFAULT_LOOKUP: &str =
+    "entry:{protocol}://{host} user:{username}; entry:{host} user:{username}";
+
+const PLACEHOLDERS: [&str; 4] = ["protocol", "host", "path", "username"];
+
+/// The attributes git sends, in their order
+#[derive(Debug, Default)]
+pub struct CredentialRequest {
+    attributes: Vec<(String, String)>,
+}
+
+#[derive(Debug, Clone)]
+pub struct CredentialLookup {
+    queries: Vec<String>,
+}
+
+impl CredentialRequest {
+    /// Reads attributes up to an empty line or the end of the input
+    pub fn read<R: BufRead>(input: R) -> std::io::Result<CredentialRequest> {
+        let mut attributes = Vec::new();
+        for line in input.lines() {
+            let line = line?;
+            let line = line.trim_end_matches('\r');
+            if line.is_empty() {
+                break;
+            }
+            if let Some(index) = line.find('=') {
+                attributes.push((line[..index].to_string(), line[index + 1..].to_string()));
+            }
+        }
+        Ok(CredentialRequest { attributes })
+    }
+
+    /// The attributes of an url like `https://registry.example.com:5000/v2/`, which may lack
+    /// the protocol or the path
+    pub fn from_url(url: &str) -> CredentialRequest {
+        let mut attributes = Vec::new();
+        let mut rest = url.trim();
+        if let Some(index) = rest.find("://") {
+            attributes.push(("protocol".to_string(), rest[..index].to_string()));
+            rest = &rest[index + 3..];
+        }
+        let (host, path) = match rest.find('/') {
+            Some(index) => (&rest[..index], rest[index + 1..].trim_end_matches('/')),
+            None => (rest, ""),
+        };
+        attributes.push(("host".to_string(), host.to_string()));
+        attributes.push(("path".to_string(), path.to_string()));
+        CredentialRequest { attributes }
+    }
+
+    /// The first value of an attribute, empty values count as missing
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(name, value)| name == key && !value.is_empty())
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Whether the name of an entry stands for the host of the request: the host itself, e.g.
+    /// `gitlab.example.com`, optionally with the protocol before and a path after it, like
+    /// `https://gitlab.example.com/group`. A name without a protocol is only used for https,
+    /// so its password is never sent unencrypted.
+    pub fn names(&self, entry_name: &str) -> bool {
+        let host = match self.get("host") {
+            Some(host) => host,
+            None => return false,
+        };
+        let protocol = self.get("protocol");
+        let rest = match entry_name.find("://") {
+            Some(index) => match protocol {
+                Some(protocol) if protocol.eq_ignore_ascii_case(&entry_name[..index]) => {
+                    &entry_name[index + 3..]
+                }
+                _ => return false,
+            },
+            None => match protocol {
+                Some(protocol) if !protocol.eq_ignore_ascii_case("https") => return false,
+                _ => entry_name,
+            },
+        };
+        let entry_host = match rest.find('/') {
+            Some(index) => &rest[..index],
+            None => rest,
+        };
+        entry_host.eq_ignore_ascii_case(host)
+    }
+}
+
+impl Display for CredentialRequest {
+    /// The request without secrets, for messages
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(
+            f,
+            "{}://{}/{}",
+            self.get("protocol").unwrap_or(""),
+            self.get("host").unwrap_or(""),
+            self.get("path").unwrap_or("")
+        )
+    }
+}
+
+impl CredentialLookup {
+    /// Checks the placeholders of the query templates
+    pub fn new(queries: Vec<String>) -> Result<CredentialLookup, String> {
+        for query in queries.iter() {
+            for placeholder in placeholders(query.as_str())? {
+                if !PLACEHOLDERS.contains(&placeholder) {
+                    return Err(format!(
+                        "Unknown placeholder {{{}}} in '{}', expected one of {{{}}}",
+                        placeholder,
+                        query,
+                        PLACEHOLDERS.join("}, {")
+                    ));
+                }
+            }
+        }
+        Ok(CredentialLookup { queries })
+    }
+
+    /// Parses a `;` separated list of query templates
+    pub fn parse(queries: &str) -> Result<CredentialLookup, String> {
+        CredentialLookup::new(
+            queries
+                .split(';')
+                .map(str::trim)
+                .filter(|query| !query.is_empty())
+                .map(str::to_string)
+                .collect(),
+        )
+    }
+
+    /// The queries for a request, in the order they should be tried.
+    ///
+    /// Queries which end up without any term are left out, they would match every entry.
+    pub fn queries(&self, request: &CredentialRequest) -> Vec<String> {
+        self.queries
+            .iter()
+            .map(|query| render(query.as_str(), request))
+            .filter(|query| !query.is_empty())
+            .collect()
+    }
+}
+
+impl Default for CredentialLookup {
+    fn default() -> Self {
+        CredentialLookup::parse(DEFAULT_LOOKUP).expect("a valid default lookup")
+    }
+}
+
+/// The entry of the first matching query, if it is named after the host of the request. Lookups
+/// are configurable, so a loose one must not hand out the password of another host.
+pub fn find_entry(
+    client: &PleasantPasswordServerClient,
+    lookup: &CredentialLookup,
+    request: &CredentialRequest,
+) -> crate::Result<Option<Credentials>> {
+    Ok(client
+        .find_entry(lookup.queries(request).as_slice())?
+        .filter(|entry| {
+            let names = request.names(entry.name.as_str());
+            if !names {
+                info!(
+                    "{} is not named after {}, it is not used",
+                    entry.name, request
+                );
+            }
+            names
+        }))
+}
+
+/// Answers a `get` request. Without a username git keeps the one it has or asks for it.
+pub fn write_credential<W: Write>(
+    mut output: W,
+    username: Option<&str>,
+    password: &str,
+) -> crate::Result<()> {
+    let attributes = [("username", username), ("password", Some(password))];
+    for (key, value) in attributes.iter() {
+        if let Some(value) = value {
+            if value.contains('\n') || value.contains('\0') {
+                return Err(
+                    format!("The {} contains a line break, git can not read it", key).into(),
+                );
+            }
+            writeln!(output, "{}={}", key, value)?;
+        }
+    }
+    Ok(())
+}
+
+/// Replaces the placeholders of every term with the quoted value of the attribute
+fn render(query: &str, request: &CredentialRequest) -> String {
+    let mut terms: Vec<String> = Vec::new();
+    'terms: for term in query.split_whitespace() {
+        if !term.contains('{') {
+            terms.push(term.to_string());
+            continue;
+        }
+        let (field, mut value) = match term.find(':') {
+            Some(index) if term[..index].chars().all(|c| c.is_ascii_alphabetic()) => {
+                (&term[..=index], term[index + 1..].to_string())
+            }
+            _ => ("", term.to_string()),
+        };
+        for placeholder in PLACEHOLDERS.iter() {
+            let pattern = format!("{{{}}}", placeholder);
+            if value.contains(pattern.as_str()) {
+                match request.get(placeholder) {
+                    Some(attribute) => value = value.replace(pattern.as_str(), attribute),
+                    None => continue 'terms,
+                }
+            }
+        }
+        terms.push(format!("{}{}", field, quote(value.as_str())));
+    }
+    terms.join(" ")
+}
+
+fn placeholders(query: &str) -> Result<Vec<&str>, String> {
+    let mut result = Vec::new();
+    let mut rest = query;
+    while let Some(start) = rest.find('{') {
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| format!("Unterminated placeholder in '{}', missing }}", query))?;
+        result.push(&rest[start + 1..start + end]);
+        rest = &rest[start + end + 1..];
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::{Filter, Query};
+
+    fn request(input: &str) -> CredentialRequest {
+        CredentialRequest::read(input.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn reads_the_attributes_up_to_an_empty_line() {
+        let request = request(
+            "protocol=https\r\nhost=gitlab.example.com\npath=\nusername=a=b\nnoise\n\nhost=other\n",
+        );
+        assert_eq!(request.get("protocol"), Some("https"));
+        assert_eq!(request.get("host"), Some("gitlab.example.com"));
+        assert_eq!(request.get("path"), None);
+        assert_eq!(request.get("username"), Some("a=b"));
+        assert_eq!(request.to_string(), "https://gitlab.example.com/");
+    }
+
+    #[test]
+    fn splits_urls() {
+        let request = CredentialRequest::from_url(" https://registry.example.com:5000/v2/ ");
+        assert_eq!(request.get("protocol"), Some("https"));
+        assert_eq!(request.get("host"), Some("registry.example.com:5000"));
+        assert_eq!(request.get("path"), Some("v2"));
+
+        let request = CredentialRequest::from_url("registry.example.com");
+        assert_eq!(request.get("protocol"), None);
+        assert_eq!(request.get("host"), Some("registry.example.com"));
+        assert_eq!(request.get("path"), None);
+    }
+
+    #[test]
+    fn renders_quoted_values_and_drops_unknown_terms() {
+        let request = request("protocol=https\nhost=git\"lab\\\n");
+        assert_eq!(
+            render(
+                "path:Root/Git entry:{host} user:{username} {protocol}",
+                &request
+            ),
+            r#"path:Root/Git entry:"git\"lab\\" "https""#
+        );
+        assert_eq!(render("user:{username}", &request), "");
+    }
+
+    #[test]
+    fn looks_up_the_exact_host() {
+        let lookup = CredentialLookup::default();
+        let queries = lookup.queries(&request("protocol=https\nhost=le.com\n"));
+        assert_eq!(
+            queries,
+            vec![r#"entry:"https://le.com""#, r#"entry:"le.com""#]
+        );
+        for query in queries.iter() {
+            let query = Query::parse(query.as_str()).unwrap();
+            assert!(query.terms.is_empty());
+            assert!(matches!(query.filters.as_slice(), [Filter::Entry(_)]));
+        }
+        assert_eq!(
+            lookup.queries(&request("protocol=https\nhost=le.com\nusername=jdoe\n"))[1],
+            r#"entry:"le.com" user:"jdoe""#
+        );
+    }
+
+    #[test]
+    fn checks_the_placeholders_of_lookups() {
+        assert!(CredentialLookup::parse("entry:{host}; path:Root {path}").is_ok());
+        assert_eq!(
+            CredentialLookup::parse("entry:{hostname}").unwrap_err(),
+            "Unknown placeholder {hostname} in 'entry:{hostname}', expected one of {protocol}, {host}, {path}, {username}"
+        );
+        assert!(CredentialLookup::parse("entry:{host").is_err());
+    }
+
+    #[test]
+    fn uses_only_entries_named_after_the_host() {
+        let https = request("protocol=https\nhost=le.com\n");
+        assert!(https.names("le.com"));
+        assert!(https.names("LE.com"));
+        assert!(https.names("https://le.com"));
+        assert!(https.names("le.com/group/project"));
+        assert!(!https.names("gitlab.example.com"));
+        assert!(!https.names("le.com.evil"));
+        assert!(!https.names("http://le.com"));
+
+        let http = request("protocol=http\nhost=le.com\n");
+        assert!(http.names("http://le.com"));
+        assert!(!http.names("le.com"));
+
+        assert!(CredentialRequest::from_url("le.com").names("le.com"));
+        assert!(!request("protocol=https\n").names("le.com"));
+    }
+
+    #[test]
+    fn refuses_values_git_can_not_read() {
+        let mut output = Vec::new();
+        write_credential(&mut output, Some("jdoe"), "s3cret").unwrap();
+        assert_eq!(output, b"username=jdoe\npassword=s3cret\n");
+        assert!(write_credential(Vec::new(), None, "two\nlines").is_err());
+    }
+}
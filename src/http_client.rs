@@ -1,5 +1,9 @@
+use crate::db::db_types::{
+    CredentialEntryChanges, FolderChanges, NewAttachment, NewCredentialEntry, NewFolder,
+};
 use crate::types::Result;
 use reqwest::{RequestBuilder, Response};
+use serde::Serialize;
 use url::Url;
 
 pub struct HttpClient {
@@ -12,12 +16,34 @@ impl HttpClient {
         HttpClient { url, client }
     }
 
-    pub async fn login(&self, login: &str, password: &str) -> Result<Response> {
+    /// Requests a token. `otp` is the provider and the one time password, if the server asked
+    /// for a second factor.
+    pub async fn login(
+        &self,
+        login: &str,
+        password: &str,
+        otp: Option<(&str, &str)>,
+    ) -> Result<Response> {
         let params = [
             ("grant_type", "password"),
             ("username", login),
             ("password", password),
         ];
+        let request = self.post("/OAuth2/token").form(&params);
+        let request = match otp {
+            Some((provider, otp)) => request
+                .header("X-Pleasant-OTP-Provider", provider)
+                .header("X-Pleasant-OTP", otp),
+            None => request,
+        };
+        Ok(request.send().await?)
+    }
+
+    pub async fn refresh(&self, refresh_token: &str) -> Result<Response> {
+        let params = [
+            ("grant_type", "refresh_token"),
+            ("refresh_token", refresh_token),
+        ];
         Ok(self.post("/OAuth2/token").form(&params).send().await?)
     }
 
@@ -33,6 +59,96 @@ impl HttpClient {
             .await?)
     }
 
+    pub async fn get_entry<S: AsRef<str>>(
+        &self,
+        access_token: S,
+        entry_id: &str,
+    ) -> Result<Response> {
+        Ok(self
+            .get(format!("api/v5/rest/Entries/{}", entry_id).as_str())
+            .bearer_auth(access_token.as_ref())
+            .send()
+            .await?)
+    }
+
+    pub async fn create_entry<S: AsRef<str>>(
+        &self,
+        access_token: S,
+        entry: &NewCredentialEntry,
+    ) -> Result<Response> {
+        Ok(self
+            .post("api/v5/rest/Entries")
+            .bearer_auth(access_token.as_ref())
+            .json(entry)
+            .send()
+            .await?)
+    }
+
+    pub async fn update_entry<S: AsRef<str>>(
+        &self,
+        access_token: S,
+        entry_id: &str,
+        changes: &CredentialEntryChanges,
+    ) -> Result<Response> {
+        Ok(self
+            .patch(format!("api/v5/rest/Entries/{}", entry_id).as_str())
+            .bearer_auth(access_token.as_ref())
+            .json(changes)
+            .send()
+            .await?)
+    }
+
+    pub async fn delete_entry<S: AsRef<str>>(
+        &self,
+        access_token: S,
+        entry_id: &str,
+    ) -> Result<Response> {
+        Ok(self
+            .delete(format!("api/v5/rest/Entries/{}", entry_id).as_str())
+            .bearer_auth(access_token.as_ref())
+            .json(&DeleteRequest::delete())
+            .send()
+            .await?)
+    }
+
+    pub async fn get_attachment<S: AsRef<str>>(
+        &self,
+        access_token: S,
+        entry_id: &str,
+        attachment_id: &str,
+    ) -> Result<Response> {
+        Ok(self
+            .get(
+                format!(
+                    "api/v5/rest/Entries/{}/attachments/{}",
+                    entry_id, attachment_id
+                )
+                .as_str(),
+            )
+            .bearer_auth(access_token.as_ref())
+            .send()
+            .await?)
+    }
+
+    pub async fn create_attachment<S: AsRef<str>>(
+        &self,
+        access_token: S,
+        attachment: &NewAttachment,
+    ) -> Result<Response> {
+        Ok(self
+            .post(
+                format!(
+                    "api/v5/rest/Entries/{}/attachments",
+                    attachment.credential_object_id
+                )
+                .as_str(),
+            )
+            .bearer_auth(access_token.as_ref())
+            .json(attachment)
+            .send()
+            .await?)
+    }
+
     pub async fn get_tree<S: AsRef<str>>(&self, access_token: S) -> Result<Response> {
         Ok(self
             .get("/api/v5/rest/folders")
@@ -41,6 +157,59 @@ impl HttpClient {
             .await?)
     }
 
+    pub async fn get_folder<S: AsRef<str>>(
+        &self,
+        access_token: S,
+        folder_id: &str,
+    ) -> Result<Response> {
+        Ok(self
+            .get(format!("api/v5/rest/folders/{}", folder_id).as_str())
+            .query(&[("recurseLevel", "0")])
+            .bearer_auth(access_token.as_ref())
+            .send()
+            .await?)
+    }
+
+    pub async fn create_folder<S: AsRef<str>>(
+        &self,
+        access_token: S,
+        folder: &NewFolder,
+    ) -> Result<Response> {
+        Ok(self
+            .post("api/v5/rest/folders")
+            .bearer_auth(access_token.as_ref())
+            .json(folder)
+            .send()
+            .await?)
+    }
+
+    pub async fn update_folder<S: AsRef<str>>(
+        &self,
+        access_token: S,
+        folder_id: &str,
+        changes: &FolderChanges,
+    ) -> Result<Response> {
+        Ok(self
+            .patch(format!("api/v5/rest/folders/{}", folder_id).as_str())
+            .bearer_auth(access_token.as_ref())
+            .json(changes)
+            .send()
+            .await?)
+    }
+
+    pub async fn delete_folder<S: AsRef<str>>(
+        &self,
+        access_token: S,
+        folder_id: &str,
+    ) -> Result<Response> {
+        Ok(self
+            .delete(format!("api/v5/rest/folders/{}", folder_id).as_str())
+            .bearer_auth(access_token.as_ref())
+            .json(&DeleteRequest::delete())
+            .send()
+            .await?)
+    }
+
     fn get(&self, path: &str) -> RequestBuilder {
         let target = self.target(path);
         self.client.get(target)
@@ -51,7 +220,34 @@ impl HttpClient {
         self.client.post(target)
     }
 
+    fn patch(&self, path: &str) -> RequestBuilder {
+        let target = self.target(path);
+        self.client.patch(target)
+    }
+
+    fn delete(&self, path: &str) -> RequestBuilder {
+        let target = self.target(path);
+        self.client.delete(target)
+    }
+
     fn target(&self, path: &str) -> Url {
         self.url.join(path).expect("Not an url")
     }
 }
+
+/// Body of a DELETE request. Pleasant either archives or deletes an object.
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct DeleteRequest {
+    action: &'static str,
+    comment: Option<String>,
+}
+
+impl DeleteRequest {
+    fn delete() -> Self {
+        DeleteRequest {
+            action: "Delete",
+            comment: None,
+        }
+    }
+}
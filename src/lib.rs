@@ -1,69 +1,126 @@
+pub mod agent;
 mod app;
+pub mod cache_policy;
+pub mod config;
 mod db;
+pub mod docker_credential;
+mod error;
+pub mod git_credential;
 mod http_client;
 mod model;
+mod otp;
+pub mod output;
+mod password_source;
+pub mod picker;
+pub mod query;
 pub mod settings;
+pub mod ssh_agent;
+pub mod template;
 mod timed_cache;
+pub mod tls;
 mod types;
 
-use crate::db::db_types::Folder;
+pub use crate::app::{data_dir, write_private_file};
+use crate::cache_policy::CachePolicy;
+use crate::db::db_types::AttachmentData;
+pub use crate::db::db_types::{
+    Attachment, CredentialEntry, CredentialEntryChanges, Folder, NewCredentialEntry,
+};
+use crate::db::db_types::{FolderChanges, NewAttachment, NewFolder};
+pub use crate::error::Error;
 use crate::http_client::HttpClient;
-use crate::model::{Credentials, PleasantPasswordModel};
+pub use crate::model::Credentials;
+use crate::model::PleasantPasswordModel;
+pub use crate::model::SyncReport;
+pub use crate::otp::OtpSource;
+pub use crate::password_source::PasswordSource;
+use crate::template::{Field, Lookup, Template};
+pub use crate::timed_cache::{CacheEntry, CacheKey};
+pub use crate::types::Result;
+use futures::future::join_all;
 use log::*;
+use reqwest::{Response, StatusCode};
 use rusqlite::Connection;
 use serde::Deserialize;
-use std::error::Error;
+use std::collections::HashMap;
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::Mutex;
 use url::Url;
 
-pub type Result<T> = std::result::Result<T, Box<dyn Error>>;
-
 pub struct PleasantPasswordServerClient {
-    login: String,
-    password: String,
+    account: Account,
+    data_dir: PathBuf,
     http_client: HttpClient,
     cache: timed_cache::TimedCache,
+    cache_policy: CachePolicy,
+    /// The access token of this process, also used if the cache is bypassed
+    session: Mutex<Option<String>>,
+}
+
+/// The body of a rejected token request
+#[derive(Deserialize)]
+struct TokenError {
+    error: Option<String>,
+    error_description: Option<String>,
+}
+
+/// The Pleasant user the client logs in as
+pub struct Account {
+    pub login: String,
+    pub password: PasswordSource,
+    pub otp: OtpSource,
 }
 
+const ACCESS_TOKEN: &str = "ACCESS_TOKEN";
+const REFRESH_TOKEN: &str = "REFRESH_TOKEN";
+/// Seconds before `expires_in` after which a cached access token is not used anymore
+const TOKEN_EXPIRY_MARGIN: i64 = 60;
+/// The server does not tell how long a refresh token is valid. A rejected one is just dropped.
+const REFRESH_TOKEN_TTL: i64 = 60 * 60 * 24 * 12;
+
 #[derive(Deserialize)]
+#[allow(dead_code)]
 struct TokenResponse {
     access_token: String,
     expires_in: i32,
     token_type: String,
+    refresh_token: Option<String>,
 }
 
 impl PleasantPasswordServerClient {
-    pub fn new(url: Url, client: reqwest::Client, login: String, password: String) -> Result<Self> {
+    /// Creates a client. The cache and the local database are kept in `data_dir`.
+    pub fn new(
+        url: Url,
+        client: reqwest::Client,
+        account: Account,
+        data_dir: PathBuf,
+        cache_key: CacheKey,
+        cache_policy: CachePolicy,
+    ) -> Result<Self> {
         Ok(PleasantPasswordServerClient {
-            login,
-            password,
+            account,
             http_client: HttpClient::new(url, client),
-            cache: timed_cache::TimedCache::open(app::app_file(
-                "pleasant_password_client",
-                "cache",
-            )?)?,
+            cache: timed_cache::TimedCache::open(data_dir.join("cache"), &cache_key)?,
+            data_dir,
+            cache_policy,
+            session: Mutex::new(None),
         })
     }
 
-    pub fn query(&self, query: &str) -> Result<Vec<Credentials>> {
-        let connection =
-            Connection::open(app::app_file("pleasant_password_client", "credentials.db")?)?;
-        let model = PleasantPasswordModel::new(connection)?;
-        model.query_for_credentials(query)
+    pub fn query(&self, query: &str, limit: Option<u32>) -> Result<Vec<Credentials>> {
+        self.model()?.query_for_credentials(query, limit)
     }
 
-    pub async fn sync(&self) -> Result<()> {
-        let connection =
-            Connection::open(app::app_file("pleasant_password_client", "credentials.db")?)?;
-        let model = PleasantPasswordModel::new(connection)?;
+    pub async fn sync(&self) -> Result<SyncReport> {
+        let model = self.model()?;
         let root_folder = self.list_entries().await?;
-        model.add_root_folder(root_folder)
+        model.sync_root_folder(root_folder)
     }
 
     pub async fn list_entries(&self) -> Result<Folder> {
-        let access_token = self.login().await?;
         let root_folder: Folder = self
-            .http_client
-            .get_tree(access_token)
+            .authorized(|access_token| self.http_client.get_tree(access_token))
             .await?
             .json()
             .await?;
@@ -71,48 +128,617 @@ impl PleasantPasswordServerClient {
     }
 
     pub async fn entry_password(&self, entry_id: &str) -> Result<Option<String>> {
-        if let Some(password) = self.cache.get(entry_id)? {
-            info!("Found password in cache");
-            return Ok(Some(password));
+        let ttl = self.password_ttl(entry_id)?;
+        if ttl.is_some() {
+            if let Some(password) = self.cache.get(entry_id)? {
+                info!("Found password in cache");
+                return Ok(Some(password));
+            }
+        } else if !self.cache_policy.bypass {
+            // the policy might have changed since the password was cached
+            self.cache.del(entry_id)?;
         }
-
-        let access_token = self.login().await?;
-        info!("Login successful");
         let response = self
-            .http_client
-            .get_entry_password(access_token, entry_id)
-            .await?
-            .text()
+            .authorized(|access_token| self.http_client.get_entry_password(access_token, entry_id))
             .await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Err(Error::NotFound(format!("Entry {} not found", entry_id)));
+        }
+        let response = response.error_for_status()?.text().await?;
 
         debug!("{}", response);
 
         // pleasants returns the password quoted, for some reasons. Maybe a json string?
         let response = response.trim_matches('"').to_string();
 
-        self.cache.put(entry_id, response.as_str(), 60 * 60 * 24);
+        if let Some(ttl) = ttl {
+            self.cache.put(entry_id, response.as_str(), ttl)?;
+        }
         Ok(Some(response))
     }
 
-    async fn login(&self) -> Result<String> {
-        info!("Login in");
-        let cached_access_key = self.cache.get("ACCESS_TOKEN")?;
-        if let Some(access_key) = cached_access_key {
-            info!("A cached access key was found.");
-            return Ok(access_key);
-        } else {
-            info!("No access key cached. Logging in");
+    /// Fetches the passwords of several entries concurrently, in the order of the ids
+    pub async fn entry_passwords(&self, entry_ids: &[String]) -> Result<Vec<String>> {
+        // log in once upfront, otherwise every request would log in on its own
+        self.login().await?;
+        let passwords = futures::future::try_join_all(
+            entry_ids
+                .iter()
+                .map(|entry_id| self.entry_password(entry_id.as_str())),
+        )
+        .await?;
+
+        entry_ids
+            .iter()
+            .zip(passwords)
+            .map(|(entry_id, password)| {
+                password.ok_or_else(|| format!("Entry {} has no password", entry_id).into())
+            })
+            .collect()
+    }
+
+    /// Fetches an entry, without its password, from the server
+    pub async fn entry(&self, entry_id: &str) -> Result<CredentialEntry> {
+        self.fetch_entry(entry_id).await
+    }
+
+    /// Renders a template, see [`template`] for the placeholder syntax.
+    ///
+    /// Fails with a list of all placeholders which could not be resolved.
+    pub async fn render_template(&self, input: &str) -> Result<String> {
+        let template = Template::parse(input)?;
+        let mut errors: Vec<String> = Vec::new();
+
+        let mut entry_ids: Vec<Option<String>> = Vec::new();
+        for placeholder in template.placeholders() {
+            let entry_id = match &placeholder.lookup {
+                Lookup::Entry(entry_id) => Ok(entry_id.clone()),
+                Lookup::Query(query) => self.resolve_entry_id(query),
+            };
+            match entry_id {
+                Ok(entry_id) => entry_ids.push(Some(entry_id)),
+                Err(err) => {
+                    errors.push(format!(
+                        "line {}: {}: {}",
+                        placeholder.line, placeholder.lookup, err
+                    ));
+                    entry_ids.push(None);
+                }
+            }
+        }
+
+        let mut password_ids: Vec<&str> = Vec::new();
+        let mut detail_ids: Vec<&str> = Vec::new();
+        for (placeholder, entry_id) in template.placeholders().zip(entry_ids.iter()) {
+            let ids = match placeholder.field {
+                Field::Id => continue,
+                Field::Password => &mut password_ids,
+                _ => &mut detail_ids,
+            };
+            if let Some(entry_id) = entry_id {
+                if !ids.contains(&entry_id.as_str()) {
+                    ids.push(entry_id.as_str());
+                }
+            }
+        }
+
+        if !password_ids.is_empty() || !detail_ids.is_empty() {
+            self.login().await?;
+        }
+        let passwords: HashMap<&str, Result<Option<String>>> = password_ids
+            .iter()
+            .cloned()
+            .zip(
+                join_all(
+                    password_ids
+                        .iter()
+                        .map(|entry_id| self.entry_password(entry_id)),
+                )
+                .await,
+            )
+            .collect();
+        let entries: HashMap<&str, Result<CredentialEntry>> = detail_ids
+            .iter()
+            .cloned()
+            .zip(join_all(detail_ids.iter().map(|entry_id| self.entry(entry_id))).await)
+            .collect();
+
+        let mut values: Vec<String> = Vec::new();
+        for (placeholder, entry_id) in template.placeholders().zip(entry_ids.iter()) {
+            let entry_id = match entry_id {
+                Some(entry_id) => entry_id.as_str(),
+                None => continue,
+            };
+            let value = match placeholder.field {
+                Field::Id => Ok(entry_id.to_string()),
+                Field::Password => match &passwords[entry_id] {
+                    Ok(Some(password)) => Ok(password.clone()),
+                    Ok(None) => Err("the entry has no password".to_string()),
+                    Err(err) => Err(err.to_string()),
+                },
+                field => match &entries[entry_id] {
+                    Ok(entry) => Ok(match field {
+                        Field::Username => entry.username.clone().unwrap_or_default(),
+                        Field::Notes => entry.notes.clone().unwrap_or_default(),
+                        _ => entry.name.clone(),
+                    }),
+                    Err(err) => Err(err.to_string()),
+                },
+            };
+            match value {
+                Ok(value) => values.push(value),
+                Err(err) => errors.push(format!(
+                    "line {}: {}: {}",
+                    placeholder.line, placeholder.lookup, err
+                )),
+            }
+        }
+
+        if !errors.is_empty() {
+            return Err(format!(
+                "Could not resolve {} placeholder(s):\n  {}",
+                errors.len(),
+                errors.join("\n  ")
+            )
+            .into());
+        }
+        Ok(template.render(values.as_slice()))
+    }
+
+    /// Resolves an entry id or a query, which has to match exactly one entry, to an entry id
+    pub fn resolve_entry_id(&self, reference: &str) -> Result<String> {
+        if is_entry_id(reference) {
+            return Ok(reference.to_string());
+        }
+
+        match self.find_entry(&[reference.to_string()])? {
+            Some(entry) => Ok(entry.id),
+            None => Err(Error::NotFound(format!(
+                "No entry matches the query '{}'",
+                reference
+            ))),
+        }
+    }
+
+    /// The entry of the first query which matches exactly one entry. Stops with an error at a
+    /// query which matches several, instead of guessing.
+    pub fn find_entry(&self, queries: &[String]) -> Result<Option<Credentials>> {
+        for query in queries.iter() {
+            let mut matches = self.query(query.as_str(), Some(2))?;
+            debug!("{} entries match {}", matches.len(), query);
+            match matches.len() {
+                0 => continue,
+                1 => return Ok(Some(matches.remove(0))),
+                _ => {
+                    return Err(format!(
+                        "The query '{}' matches more than one entry, e.g. {}/{} and {}/{}",
+                        query,
+                        matches[0].folder_path,
+                        matches[0].name,
+                        matches[1].folder_path,
+                        matches[1].name
+                    )
+                    .into())
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Lists the cached keys, e.g. entry ids and `ACCESS_TOKEN`, with their expiry dates
+    pub fn cache_entries(&self) -> Result<Vec<CacheEntry>> {
+        Ok(self.cache.list()?)
+    }
+
+    /// Removes everything from the cache, including the access token
+    pub fn clear_cache(&self) -> Result<usize> {
+        Ok(self.cache.clear()?)
+    }
+
+    pub fn purge_expired_cache(&self) -> Result<usize> {
+        Ok(self.cache.purge_expired()?)
+    }
+
+    /// Removes the cached password of an entry. Returns false if it was not cached.
+    pub fn forget_password(&self, entry_id: &str) -> Result<bool> {
+        Ok(self.cache.del(entry_id)?)
+    }
+
+    /// Creates a new credentials entry and returns its id
+    pub async fn create_entry(&self, entry: NewCredentialEntry) -> Result<String> {
+        let entry_id: String = self
+            .authorized(|access_token| self.http_client.create_entry(access_token, &entry))
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        info!("Created entry {}", entry_id);
+
+        self.store_entry(entry_id.as_str()).await?;
+        Ok(entry_id)
+    }
+
+    pub async fn update_entry(
+        &self,
+        entry_id: &str,
+        changes: CredentialEntryChanges,
+    ) -> Result<()> {
+        self.authorized(|access_token| {
+            self.http_client
+                .update_entry(access_token, entry_id, &changes)
+        })
+        .await?
+        .error_for_status()?;
+        info!("Updated entry {}", entry_id);
+
+        // the cached password would be stale otherwise
+        if changes.password.is_some() {
+            self.cache.del(entry_id)?;
         }
+        self.store_entry(entry_id).await
+    }
+
+    pub async fn delete_entry(&self, entry_id: &str) -> Result<()> {
+        self.authorized(|access_token| self.http_client.delete_entry(access_token, entry_id))
+            .await?
+            .error_for_status()?;
+        info!("Deleted entry {}", entry_id);
 
-        let response: TokenResponse = self
-            .http_client
-            .login(self.login.as_ref(), self.password.as_ref())
+        self.cache.del(entry_id)?;
+        self.model()?.delete_credentials(entry_id)
+    }
+
+    /// Resolves the full path of a folder, e.g. Root/Team/Prod, with the local database
+    pub fn folder_id(&self, path: &str) -> Result<String> {
+        self.model()?.folder_id(path)?.ok_or_else(|| {
+            Error::NotFound(format!(
+                "Folder {} not found, maybe the local database needs a sync",
+                path
+            ))
+        })
+    }
+
+    /// The full paths of all folders in the local database
+    pub fn folder_paths(&self) -> Result<Vec<String>> {
+        self.model()?.folder_paths()
+    }
+
+    /// Lists the attachments of an entry as known by the local database
+    pub fn attachments(&self, entry_id: &str) -> Result<Vec<Attachment>> {
+        self.model()?.attachments(entry_id)
+    }
+
+    /// Downloads an attachment. It is looked up by its id or file name in the local database.
+    pub async fn download_attachment(&self, entry_id: &str, attachment: &str) -> Result<Vec<u8>> {
+        let attachment_id = self
+            .attachments(entry_id)?
+            .into_iter()
+            .find(|a| a.attachment_id == attachment || a.file_name == attachment)
+            .map(|a| a.attachment_id)
+            .ok_or_else(|| {
+                Error::NotFound(format!(
+                    "No attachment {} found for entry {}. Maybe a sync is required?",
+                    attachment, entry_id
+                ))
+            })?;
+        let data: AttachmentData = self
+            .authorized(|access_token| {
+                self.http_client
+                    .get_attachment(access_token, entry_id, attachment_id.as_str())
+            })
             .await?
+            .error_for_status()?
             .json()
             .await?;
+        debug!("Downloaded attachment {}", data.file_name);
+        Ok(base64::decode(data.file_data)?)
+    }
 
-        self.cache
-            .put("ACCESS_TOKEN", response.access_token.as_str(), 1036799);
-        return Ok(response.access_token);
+    /// Uploads a new attachment to an entry and returns the id of the attachment
+    pub async fn upload_attachment(
+        &self,
+        entry_id: &str,
+        file_name: &str,
+        content: &[u8],
+    ) -> Result<String> {
+        let attachment = NewAttachment {
+            credential_object_id: entry_id.to_string(),
+            file_name: file_name.to_string(),
+            file_data: base64::encode(content),
+        };
+        let attachment_id: String = self
+            .authorized(|access_token| {
+                self.http_client
+                    .create_attachment(access_token, &attachment)
+            })
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        info!("Uploaded attachment {}", attachment_id);
+
+        self.store_entry(entry_id).await?;
+        Ok(attachment_id)
     }
+
+    /// Creates a new folder below `parent_id` and returns its id
+    pub async fn create_folder(&self, name: &str, parent_id: &str) -> Result<String> {
+        let folder = NewFolder {
+            name: name.to_string(),
+            parent_id: parent_id.to_string(),
+        };
+        let folder_id: String = self
+            .authorized(|access_token| self.http_client.create_folder(access_token, &folder))
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        info!("Created folder {}", folder_id);
+
+        self.store_folder(folder_id.as_str()).await?;
+        Ok(folder_id)
+    }
+
+    pub async fn rename_folder(&self, folder_id: &str, name: &str) -> Result<()> {
+        let changes = FolderChanges {
+            name: Some(name.to_string()),
+            ..Default::default()
+        };
+        self.update_folder(folder_id, changes).await
+    }
+
+    pub async fn move_folder(&self, folder_id: &str, parent_id: &str) -> Result<()> {
+        let changes = FolderChanges {
+            parent_id: Some(parent_id.to_string()),
+            ..Default::default()
+        };
+        self.update_folder(folder_id, changes).await
+    }
+
+    /// Deletes a folder including everything it contains
+    pub async fn delete_folder(&self, folder_id: &str) -> Result<()> {
+        self.authorized(|access_token| self.http_client.delete_folder(access_token, folder_id))
+            .await?
+            .error_for_status()?;
+        info!("Deleted folder {}", folder_id);
+
+        self.model()?.delete_folder(folder_id)
+    }
+
+    async fn update_folder(&self, folder_id: &str, changes: FolderChanges) -> Result<()> {
+        self.authorized(|access_token| {
+            self.http_client
+                .update_folder(access_token, folder_id, &changes)
+        })
+        .await?
+        .error_for_status()?;
+        info!("Updated folder {}", folder_id);
+
+        self.store_folder(folder_id).await
+    }
+
+    /// Fetches a folder from the server and stores it in the local database
+    async fn store_folder(&self, folder_id: &str) -> Result<()> {
+        let folder: Folder = self
+            .authorized(|access_token| self.http_client.get_folder(access_token, folder_id))
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        self.model()?.save_folder(&folder)
+    }
+
+    /// Fetches an entry from the server and stores it in the local database
+    async fn store_entry(&self, entry_id: &str) -> Result<()> {
+        let entry = self.fetch_entry(entry_id).await?;
+        self.model()?.save_credentials(entry)
+    }
+
+    async fn fetch_entry(&self, entry_id: &str) -> Result<CredentialEntry> {
+        Ok(self
+            .authorized(|access_token| self.http_client.get_entry(access_token, entry_id))
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+
+    /// The cache lifetime of a password according to the policy, `None` if it must not be cached
+    fn password_ttl(&self, entry_id: &str) -> Result<Option<i64>> {
+        if !self.cache_policy.has_rules() || self.cache_policy.bypass {
+            return Ok(self.cache_policy.password_ttl(entry_id, None, None));
+        }
+        // the rules match folder paths and names, which are only known after a sync
+        let credentials = self.model()?.credentials_by_id(entry_id)?;
+        Ok(self.cache_policy.password_ttl(
+            entry_id,
+            credentials.as_ref().map(|c| c.folder_path.as_str()),
+            credentials.as_ref().map(|c| c.name.as_str()),
+        ))
+    }
+
+    fn model(&self) -> Result<PleasantPasswordModel> {
+        let connection = Connection::open(self.data_dir.join("credentials.db"))?;
+        PleasantPasswordModel::new(connection)
+    }
+
+    /// Logs in right away instead of with the first request, e.g. to ask for the password while
+    /// there still is a terminal
+    pub async fn log_in(&self) -> Result<()> {
+        self.login().await.map(|_| ())
+    }
+
+    /// Logs in with this password instead of the one of the account. Fails if the server
+    /// rejects it. Without a one time password the one of the account is used, if needed.
+    pub async fn log_in_with(&self, password: &str, otp: Option<&str>) -> Result<()> {
+        let response = match otp {
+            Some(code) => {
+                self.login_with(password, &OtpSource::Code(code.to_string()))
+                    .await?
+            }
+            None => self.login_with(password, &self.account.otp).await?,
+        };
+        self.store_token(response).map(|_| ())
+    }
+
+    pub fn account(&self) -> &Account {
+        &self.account
+    }
+
+    /// Forgets the access token of this process, the cached one is kept
+    pub fn end_session(&self) {
+        *self.session.lock().unwrap() = None;
+    }
+
+    /// Sends a request with an access token. If the server rejects the token, e.g. because it
+    /// was revoked, the token is evicted and the request is retried once with a new one.
+    async fn authorized<F, Fut>(&self, request: F) -> Result<Response>
+    where
+        F: Fn(String) -> Fut,
+        Fut: Future<Output = Result<Response>>,
+    {
+        let response = request(self.login().await?).await?;
+        if response.status() != StatusCode::UNAUTHORIZED {
+            return Ok(response);
+        }
+
+        info!("The access token was rejected. Logging in again");
+        self.forget_access_token()?;
+        request(self.login().await?).await
+    }
+
+    async fn login(&self) -> Result<String> {
+        info!("Login in");
+        if let Some(access_token) = self.session.lock().unwrap().clone() {
+            return Ok(access_token);
+        }
+        if !self.cache_policy.bypass {
+            if let Some(access_key) = self.cache.get(ACCESS_TOKEN)? {
+                info!("A cached access key was found.");
+                *self.session.lock().unwrap() = Some(access_key.clone());
+                return Ok(access_key);
+            }
+        }
+
+        let response = match self.refresh_login().await? {
+            Some(response) => response,
+            None => {
+                info!("No access key cached. Logging in");
+                self.password_login().await?
+            }
+        };
+        self.store_token(response)
+    }
+
+    /// Logs in with login and password. If two-factor authentication is enabled, the server
+    /// answers with a challenge and the request is repeated with a one time password.
+    async fn password_login(&self) -> Result<TokenResponse> {
+        let password = self
+            .account
+            .password
+            .password(self.account.login.as_str())?;
+        self.login_with(password.as_str(), &self.account.otp).await
+    }
+
+    async fn login_with(&self, password: &str, otp: &OtpSource) -> Result<TokenResponse> {
+        let login = self.account.login.as_str();
+        let response = self.http_client.login(login, password, None).await?;
+
+        let response = match otp_provider(&response) {
+            Some(provider) => {
+                info!("The server requires a one time password of {}", provider);
+                let otp = otp.one_time_password(provider.as_str())?;
+                self.http_client
+                    .login(login, password, Some((provider.as_str(), otp.as_str())))
+                    .await?
+            }
+            None => response,
+        };
+
+        let status = response.status();
+        if status == StatusCode::BAD_REQUEST || status == StatusCode::UNAUTHORIZED {
+            let description = response
+                .json::<TokenError>()
+                .await
+                .ok()
+                .and_then(|error| error.error_description.or(error.error))
+                .unwrap_or_else(|| status.to_string());
+            return Err(Error::Authentication(description));
+        }
+        Ok(response.error_for_status()?.json().await?)
+    }
+
+    /// Logs in with a cached refresh token. Returns `None` if there is none or it is not
+    /// accepted anymore.
+    async fn refresh_login(&self) -> Result<Option<TokenResponse>> {
+        if self.cache_policy.bypass {
+            return Ok(None);
+        }
+        let refresh_token = match self.cache.get(REFRESH_TOKEN)? {
+            Some(refresh_token) => refresh_token,
+            None => return Ok(None),
+        };
+
+        info!("Refreshing the access token");
+        let response = self.http_client.refresh(refresh_token.as_str()).await?;
+        if !response.status().is_success() {
+            info!("The refresh token was rejected: {}", response.status());
+            self.cache.del(REFRESH_TOKEN)?;
+            return Ok(None);
+        }
+        Ok(Some(response.json().await?))
+    }
+
+    fn store_token(&self, response: TokenResponse) -> Result<String> {
+        *self.session.lock().unwrap() = Some(response.access_token.clone());
+        if self.cache_policy.bypass {
+            return Ok(response.access_token);
+        }
+
+        // expire a bit early, so a token is not used right before the server drops it
+        let ttl = i64::from(response.expires_in) - TOKEN_EXPIRY_MARGIN;
+        if ttl > 0 {
+            self.cache
+                .put(ACCESS_TOKEN, response.access_token.as_str(), ttl)?;
+        }
+        if let Some(refresh_token) = response.refresh_token {
+            self.cache
+                .put(REFRESH_TOKEN, refresh_token.as_str(), REFRESH_TOKEN_TTL)?;
+        }
+        Ok(response.access_token)
+    }
+
+    fn forget_access_token(&self) -> Result<()> {
+        *self.session.lock().unwrap() = None;
+        if !self.cache_policy.bypass {
+            self.cache.del(ACCESS_TOKEN)?;
+        }
+        Ok(())
+    }
+}
+
+/// The provider of the second factor, if the response is a two-factor challenge
+fn otp_provider(response: &Response) -> Option<String> {
+    let headers = response.headers();
+    let required = headers
+        .get("X-Pleasant-OTP")
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.eq_ignore_ascii_case("required"))
+        .unwrap_or(false);
+    if !required {
+        return None;
+    }
+    headers
+        .get("X-Pleasant-OTP-Provider")
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+}
+
+/// Entry and folder ids are uuids like 94153de4-1cba-4c13-9c23-41cde415146b
+fn is_entry_id(value: &str) -> bool {
+    value.len() == 36
+        && value.chars().enumerate().all(|(i, c)| match i {
+            8 | 13 | 18 | 23 => c == '-',
+            _ => c.is_ascii_hexdigit(),
+        })
 }
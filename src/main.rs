@@ -1,78 +1,1387 @@
 use log::info;
+use pleasent_keepass_client_rs::agent::{self, AgentClient, AgentRequest};
+use pleasent_keepass_client_rs::cache_policy::{
+    parse_ttl, CachePolicy, CacheRule, DEFAULT_PASSWORD_TTL,
+};
+use pleasent_keepass_client_rs::config::{ConfigFile, Profile};
+use pleasent_keepass_client_rs::docker_credential::{self, CREDENTIALS_NOT_FOUND};
+use pleasent_keepass_client_rs::git_credential::{
+    self, write_credential, CredentialLookup, CredentialRequest,
+};
+use pleasent_keepass_client_rs::output::{write_records, write_tree, OutputFormat};
+use pleasent_keepass_client_rs::picker::{self, Action, Field};
+use pleasent_keepass_client_rs::query::quote;
 use pleasent_keepass_client_rs::settings::{
-    optional_url, require_secure_string, require_string, require_url,
+    optional_secure_string, optional_string, optional_url, parse_url, SecureString, SettingError,
+    SettingErrors, Validation,
 };
-use pleasent_keepass_client_rs::PleasantPasswordServerClient;
+use pleasent_keepass_client_rs::ssh_agent;
+use pleasent_keepass_client_rs::tls::{self, ClientCertificate, TlsOptions, TlsVersion};
 use pleasent_keepass_client_rs::Result;
+use pleasent_keepass_client_rs::{
+    data_dir, write_private_file, Account, CacheKey, CredentialEntryChanges, Credentials, Error,
+    NewCredentialEntry, OtpSource, PasswordSource, PleasantPasswordServerClient,
+};
 use reqwest::Proxy;
+use serde::Serialize;
+use std::ffi::OsString;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use structopt::StructOpt;
+use url::Url;
 
 #[derive(StructOpt, Debug)]
 #[structopt(about = "pleasant password client")]
-enum Args {
+struct Args {
+    #[structopt(
+        long,
+        global = true,
+        possible_values = OutputFormat::VARIANTS,
+        case_insensitive = true,
+        help = "output format of query, tree, get-password and attachment list"
+    )]
+    output: Option<OutputFormat>,
+    #[structopt(
+        long,
+        global = true,
+        help = "neither read nor write cached passwords and access tokens, also skips the agent"
+    )]
+    no_cache: bool,
+    #[structopt(
+        long,
+        global = true,
+        help = "do not ask a running agent, log in and open the databases in this process"
+    )]
+    no_agent: bool,
+    #[structopt(
+        long,
+        global = true,
+        help = "one time password, if the account uses two-factor authentication"
+    )]
+    otp: Option<String>,
+    #[structopt(
+        long,
+        global = true,
+        help = "read the password of the login from the first line of stdin"
+    )]
+    password_stdin: bool,
+    #[structopt(
+        long,
+        global = true,
+        help = "profile of the config file, defaults to PLEASANT_PASSWORD_PROFILE or default_profile"
+    )]
+    profile: Option<String>,
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+#[derive(StructOpt, Debug)]
+enum Command {
     #[structopt(about = "retrieve the password for an entry id", alias = "pw")]
     GetPassword { entry_id: String },
-    #[structopt(about = "(debug) list the entry tree")]
+    #[structopt(about = "list the entry tree")]
     Tree {},
     #[structopt(about = "download all credentials entries (without passwords)")]
     Sync {},
-    #[structopt(about = "query for entries")]
-    Query { query: String },
+    #[structopt(about = "query for entries, best matches first")]
+    Query {
+        query: String,
+        #[structopt(long, help = "return at most this many entries")]
+        limit: Option<u32>,
+    },
+    #[structopt(
+        about = "pick an entry in a fuzzy finder and print or copy its password, username or id",
+        alias = "browse"
+    )]
+    Pick {
+        #[structopt(help = "initial filter")]
+        query: Option<String>,
+        #[structopt(
+            long,
+            help = "copy to the clipboard of the terminal (OSC 52) with Enter, Ctrl-U and Ctrl-E, print with Alt"
+        )]
+        copy: bool,
+    },
+    #[structopt(about = "create a new credentials entry and print its id")]
+    Create {
+        #[structopt(long, help = "id of the folder the entry is created in")]
+        folder_id: String,
+        #[structopt(long)]
+        name: String,
+        #[structopt(long)]
+        username: Option<String>,
+        #[structopt(long, help = "the password, use - to read it from stdin")]
+        password: Option<String>,
+        #[structopt(long)]
+        notes: Option<String>,
+    },
+    #[structopt(about = "change fields of a credentials entry")]
+    Edit {
+        entry_id: String,
+        #[structopt(long)]
+        name: Option<String>,
+        #[structopt(long)]
+        username: Option<String>,
+        #[structopt(long, help = "the new password, use - to read it from stdin")]
+        password: Option<String>,
+        #[structopt(long)]
+        notes: Option<String>,
+    },
+    #[structopt(about = "delete a credentials entry")]
+    Delete { entry_id: String },
+    #[structopt(about = "run a command with passwords of entries in its environment")]
+    Exec {
+        #[structopt(
+            long,
+            short,
+            number_of_values = 1,
+            help = "NAME=<entry-id-or-query>, sets the variable NAME to the password of the entry"
+        )]
+        env: Vec<String>,
+        #[structopt(required = true, last = true)]
+        command: Vec<String>,
+    },
+    #[structopt(
+        about = "replace placeholders like {{ pleasant \"<entry-id>\" \"password\" }} in a template"
+    )]
+    Render {
+        #[structopt(parse(from_os_str))]
+        template: PathBuf,
+        #[structopt(
+            long,
+            short,
+            parse(from_os_str),
+            help = "write the result into this file, readable only by the current user, instead of stdout"
+        )]
+        file: Option<PathBuf>,
+    },
+    #[structopt(
+        about = "git credential helper, e.g. git config credential.helper 'pleasent-keepass-client-rs git-credential'"
+    )]
+    GitCredential {
+        #[structopt(help = "get, store or erase, other operations are ignored")]
+        operation: String,
+    },
+    #[structopt(
+        about = "Docker credential helper, also run as docker-credential-pleasant, e.g. through a symlink"
+    )]
+    DockerCredential {
+        #[structopt(help = "get, list, store or erase")]
+        operation: String,
+    },
+    #[structopt(about = "keep a logged in client running, get-password and query use it")]
+    Agent {
+        #[structopt(subcommand)]
+        command: AgentCommand,
+    },
+    #[structopt(
+        about = "SSH agent for the private keys of the entries matching PLEASANT_PASSWORD_SSH_QUERY or PLEASANT_PASSWORD_SSH_FOLDER"
+    )]
+    SshAgent {
+        #[structopt(
+            long,
+            parse(from_os_str),
+            help = "path of the socket, by default ssh-agent.sock in the data directory"
+        )]
+        socket: Option<PathBuf>,
+    },
+    #[structopt(about = "inspect and invalidate the local password cache")]
+    Cache {
+        #[structopt(subcommand)]
+        command: CacheCommand,
+    },
+    #[structopt(about = "manage folders")]
+    Folder {
+        #[structopt(subcommand)]
+        command: FolderCommand,
+    },
+    #[structopt(about = "list, download and upload entry attachments")]
+    Attachment {
+        #[structopt(subcommand)]
+        command: AttachmentCommand,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+enum CacheCommand {
+    #[structopt(about = "remove all cached passwords and the access token")]
+    Clear {},
+    #[structopt(about = "remove expired entries")]
+    PurgeExpired {},
+    #[structopt(about = "list cached keys and their expiry, never the values")]
+    List {},
+    #[structopt(about = "remove the cached password of an entry, e.g. after it was rotated")]
+    Forget { entry_id: String },
+}
+
+#[derive(StructOpt, Debug)]
+enum AgentCommand {
+    #[structopt(about = "log in and answer requests until stopped or idle")]
+    Start {
+        #[structopt(
+            long,
+            default_value = "1h",
+            help = "stop after this long without a request, e.g. 30m, 8h or never"
+        )]
+        idle_timeout: String,
+    },
+    #[structopt(about = "stop the running agent")]
+    Stop {},
+    #[structopt(about = "refuse requests until unlocked with the password of the login")]
+    Lock {},
+    #[structopt(about = "unlock the agent, the password is asked for like for a login")]
+    Unlock {},
+}
+
+#[derive(StructOpt, Debug)]
+enum FolderCommand {
+    #[structopt(about = "create a new folder and print its id")]
+    Create {
+        #[structopt(long, help = "id of the parent folder")]
+        parent_id: String,
+        name: String,
+    },
+    #[structopt(about = "rename a folder")]
+    Rename { folder_id: String, name: String },
+    #[structopt(about = "move a folder below another parent folder")]
+    Move {
+        folder_id: String,
+        parent_id: String,
+    },
+    #[structopt(about = "delete a folder including all its entries and sub folders")]
+    Delete { folder_id: String },
+}
+
+#[derive(StructOpt, Debug)]
+enum AttachmentCommand {
+    #[structopt(about = "list the attachments of an entry")]
+    List { entry_id: String },
+    #[structopt(about = "download an attachment by file name or id")]
+    Get {
+        entry_id: String,
+        attachment: String,
+        #[structopt(
+            long,
+            short,
+            parse(from_os_str),
+            help = "write the attachment into this file instead of stdout"
+        )]
+        file: Option<PathBuf>,
+    },
+    #[structopt(about = "upload a file as new attachment of an entry")]
+    Put {
+        entry_id: String,
+        #[structopt(parse(from_os_str))]
+        path: PathBuf,
+        #[structopt(
+            long,
+            help = "file name of the attachment, defaults to the name of the file"
+        )]
+        name: Option<String>,
+    },
+}
+
+/// Exit codes per error class, see [`exit_code`]. `exec` exits with the code of the command.
+const EXIT_ERROR: i32 = 1;
+const EXIT_MISSING_SETTING: i32 = 10;
+const EXIT_SETTING_NOT_UNICODE: i32 = 11;
+const EXIT_INVALID_URL: i32 = 12;
+const EXIT_INVALID_SETTING: i32 = 13;
+const EXIT_AUTHENTICATION: i32 = 20;
+const EXIT_ACCESS_DENIED: i32 = 21;
+const EXIT_NOT_FOUND: i32 = 22;
+const EXIT_NETWORK: i32 = 30;
+const EXIT_SERVER: i32 = 31;
+const EXIT_CACHE: i32 = 40;
+const EXIT_DATABASE: i32 = 41;
+
+/// All settings after merging the flags, the environment and the profile
+struct Settings {
+    profile_name: Option<String>,
+    url: Url,
+    http_proxy: Option<Url>,
+    https_proxy: Option<Url>,
+    login: String,
+    password: Option<SecureString>,
+    password_command: Option<String>,
+    totp_secret: Option<SecureString>,
+    cache_secret: Option<SecureString>,
+    cache_key_file: Option<String>,
+    password_ttl: Option<i64>,
+    cache_rules: Vec<CacheRule>,
+    /// format of the listing commands, the flag or else the profile
+    output: Option<OutputFormat>,
+    tls: TlsOptions,
+    git_credential_lookup: CredentialLookup,
+    docker_credential_lookup: CredentialLookup,
+    /// full path of the folder for entries created by `docker login`
+    docker_folder: Option<String>,
+    /// query for the entries holding the keys of `ssh-agent`
+    ssh_query: Option<String>,
+    /// full path of the folder with the entries holding the keys of `ssh-agent`
+    ssh_folder: Option<String>,
 }
 
 #[tokio::main]
-async fn main() -> Result<()> {
+async fn main() {
+    if let Err(err) = run().await {
+        eprintln!("ERROR: {}", err);
+        std::process::exit(exit_code(&err));
+    }
+}
+
+/// | Code | Error                                  |
+/// |------|----------------------------------------|
+/// | 1    | any other error                        |
+/// | 10   | a required setting is missing          |
+/// | 11   | a setting is not valid utf8            |
+/// | 12   | a setting is not a valid url           |
+/// | 13   | any other invalid setting              |
+/// | 20   | the login was rejected                 |
+/// | 21   | access to an entry or folder denied    |
+/// | 22   | entry, folder or attachment not found  |
+/// | 30   | the server could not be reached        |
+/// | 31   | the server answered with an error      |
+/// | 40   | the cache could not be used            |
+/// | 41   | the local database could not be used   |
+///
+/// With several invalid settings the code belongs to the first one.
+fn exit_code(err: &Error) -> i32 {
+    match err {
+        Error::Settings(errors) => match errors.0.first() {
+            Some(SettingError::Missing { .. }) => EXIT_MISSING_SETTING,
+            Some(SettingError::NotUnicode { .. }) => EXIT_SETTING_NOT_UNICODE,
+            Some(SettingError::InvalidUrl { .. }) => EXIT_INVALID_URL,
+            Some(SettingError::Invalid { .. }) | None => EXIT_INVALID_SETTING,
+        },
+        Error::Authentication(_) => EXIT_AUTHENTICATION,
+        Error::AccessDenied(_) => EXIT_ACCESS_DENIED,
+        Error::NotFound(_) => EXIT_NOT_FOUND,
+        Error::Network(_) => EXIT_NETWORK,
+        Error::Server(_) => EXIT_SERVER,
+        Error::Cache(_) => EXIT_CACHE,
+        Error::Database(_) => EXIT_DATABASE,
+        Error::Io(_) | Error::Other(_) => EXIT_ERROR,
+    }
+}
+
+async fn run() -> Result<()> {
     dotenv::dotenv().ok();
     pretty_env_logger::init_timed();
+    let args: Args = Args::from_iter(arguments());
+    let settings = load_settings(&args)?;
+    let output = settings.output;
+    // scripts read the plain password, so only the flag changes its format, never the profile
+    let password_output = args.output;
+    let git_credential_lookup = settings.git_credential_lookup;
+    let docker_credential_lookup = settings.docker_credential_lookup;
+    let docker_folder = settings.docker_folder;
+    let ssh_query = settings.ssh_query;
+    let ssh_folder = settings.ssh_folder;
 
-    let url = require_url("PLEASANT_PASSWORD_SERVER_URL");
-    let http_proxy = optional_url("HTTP_PROXY");
-    let https_proxy = optional_url("HTTPS_PROXY");
-    let login = require_string("PLEASANT_PASSWORD_SERVER_LOGIN");
-    let password = require_secure_string("PLEASANT_PASSWORD_SERVER_PASSWORD");
-
-    let mut client = reqwest::Client::builder();
-    let mut client = if let Some(proxy_url) = http_proxy {
+    let client = reqwest::Client::builder();
+    let client = if let Some(proxy_url) = settings.http_proxy {
         client.proxy(Proxy::http(proxy_url)?)
     } else {
         client
     };
 
-    let mut client = if let Some(proxy_url) = https_proxy {
+    let client = if let Some(proxy_url) = settings.https_proxy {
         client.proxy(Proxy::https(proxy_url)?)
     } else {
         client
     };
 
-    let client = client.build()?;
+    let client = tls::configure(client, &settings.tls)?.build()?;
 
-    let client =
-        PleasantPasswordServerClient::new(url, client, login, password.as_str().to_string())
-            .expect("Could not create client");
+    // every profile has its own cache and database, so data of different servers never mixes
+    let data_dir = data_dir(settings.profile_name.as_deref())?;
+    let agent_socket = data_dir.join(agent::SOCKET_NAME);
+    // like ssh-agent, a running agent answers before anything asks for a password. It answers
+    // from its cache, so it is skipped when the cache should be.
+    if !args.no_agent
+        && !args.no_cache
+        && ask_agent(&agent_socket, &args.command, password_output, output)?
+    {
+        return Ok(());
+    }
+    let cache_key = match settings.cache_secret {
+        Some(secret) => CacheKey::Secret(secret),
+        None => CacheKey::KeyFile(match settings.cache_key_file {
+            Some(path) => PathBuf::from(path),
+            None => data_dir.join("cache.key"),
+        }),
+    };
 
-    let args: Args = Args::from_args();
+    let password = if args.password_stdin {
+        PasswordSource::Value(read_password_stdin()?)
+    } else if let Some(password) = settings.password {
+        PasswordSource::Value(password)
+    } else if let Some(command) = settings.password_command {
+        PasswordSource::Command(command)
+    } else {
+        PasswordSource::Prompt
+    };
 
-    match args {
-        Args::GetPassword { entry_id } => print_password(client, entry_id).await?,
-        Args::Tree {} => println!("{:#?}", client.list_entries().await?),
-        Args::Sync {} => client.sync().await?,
-        Args::Query { query } => print_query(client, query)?,
+    let otp = match (args.otp, settings.totp_secret) {
+        (Some(code), _) => OtpSource::Code(code),
+        (None, Some(secret)) => OtpSource::TotpSecret(secret),
+        (None, None) => OtpSource::Prompt,
+    };
+
+    let cache_policy = CachePolicy {
+        password_ttl: settings.password_ttl,
+        rules: settings.cache_rules,
+        // the keys of the ssh agent must never end up on disk
+        bypass: args.no_cache || matches!(args.command, Command::SshAgent { .. }),
+    };
+
+    let ssh_agent_socket = data_dir.join(ssh_agent::SOCKET_NAME);
+    let client = PleasantPasswordServerClient::new(
+        settings.url,
+        client,
+        Account {
+            login: settings.login,
+            password,
+            otp,
+        },
+        data_dir,
+        cache_key,
+        cache_policy,
+    )?;
+
+    match args.command {
+        Command::GetPassword { entry_id } => {
+            let password = client.entry_password(entry_id.as_str()).await?;
+            print_password(entry_id, password, password_output)?
+        }
+        Command::Tree {} => write_tree(
+            output.unwrap_or(OutputFormat::Table),
+            &client.list_entries().await?,
+            std::io::stdout(),
+        )?,
+        Command::Sync {} => println!("Sync finished: {}", client.sync().await?),
+        Command::Query { query, limit } => {
+            print_query(client.query(query.as_str(), limit)?, output)?
+        }
+        Command::Pick { query, copy } => {
+            pick(
+                client,
+                query.as_deref().unwrap_or(""),
+                copy,
+                password_output,
+            )
+            .await?
+        }
+        Command::Create {
+            folder_id,
+            name,
+            username,
+            password,
+            notes,
+        } => {
+            let entry = NewCredentialEntry {
+                name,
+                username,
+                password: password.map(read_password_arg).transpose()?,
+                notes,
+                group_id: folder_id,
+            };
+            println!("{}", client.create_entry(entry).await?)
+        }
+        Command::Edit {
+            entry_id,
+            name,
+            username,
+            password,
+            notes,
+        } => {
+            let changes = CredentialEntryChanges {
+                name,
+                username,
+                password: password.map(read_password_arg).transpose()?,
+                notes,
+            };
+            client.update_entry(entry_id.as_str(), changes).await?
+        }
+        Command::Delete { entry_id } => client.delete_entry(entry_id.as_str()).await?,
+        Command::Exec { env, command } => exec(client, env, command).await?,
+        Command::Render { template, file } => {
+            let rendered = client
+                .render_template(std::fs::read_to_string(&template)?.as_str())
+                .await?;
+            match file {
+                Some(path) => write_private_file(path, rendered.as_bytes())?,
+                None => print!("{}", rendered),
+            }
+        }
+        Command::GitCredential { operation } => {
+            git_credential(client, operation.as_str(), &git_credential_lookup).await?
+        }
+        Command::DockerCredential { operation } => {
+            let result = docker_credential(
+                client,
+                operation.as_str(),
+                &docker_credential_lookup,
+                docker_folder.as_deref(),
+            )
+            .await;
+            // Docker shows what the helper prints to stdout, not stderr
+            if let Err(err) = result {
+                println!("{}", err);
+                std::process::exit(exit_code(&err));
+            }
+        }
+        Command::Agent { command } => manage_agent(client, &agent_socket, command).await?,
+        Command::SshAgent { socket } => {
+            start_ssh_agent(
+                client,
+                socket.as_deref().unwrap_or(ssh_agent_socket.as_path()),
+                ssh_query.as_deref(),
+                ssh_folder.as_deref(),
+            )
+            .await?
+        }
+        Command::Cache { command } => manage_cache(client, command, output)?,
+        Command::Folder { command } => manage_folder(client, command).await?,
+        Command::Attachment { command } => manage_attachment(client, command, output).await?,
     };
 
     Ok(())
 }
 
-fn print_query(client: PleasantPasswordServerClient, query: String) -> Result<()> {
-    let mut writer = csv::Writer::from_writer(std::io::stdout());
-    for cred in client.query(query.as_str())?.into_iter() {
-        writer.serialize(cred)?;
+#[derive(Serialize)]
+struct EntryPassword {
+    id: String,
+    password: String,
+}
+
+fn print_query(credentials: Vec<Credentials>, output: Option<OutputFormat>) -> Result<()> {
+    write_records(
+        output.unwrap_or(OutputFormat::Csv),
+        credentials.as_slice(),
+        std::io::stdout(),
+    )
+}
+
+fn print_password(
+    entry_id: String,
+    password: Option<String>,
+    output: Option<OutputFormat>,
+) -> Result<()> {
+    // 94153de4-1cba-4c13-9c23-41cde415146b
+    let password =
+        password.ok_or_else(|| Error::NotFound(format!("Entry {} has no password", entry_id)))?;
+    match output {
+        None => println!("{}", password),
+        Some(format) => write_records(
+            format,
+            &[EntryPassword {
+                id: entry_id,
+                password,
+            }],
+            std::io::stdout(),
+        )?,
     }
     Ok(())
 }
 
-async fn print_password(client: PleasantPasswordServerClient, entry_id: String) -> Result<()> {
-    // 94153de4-1cba-4c13-9c23-41cde415146b
-    let password = client.entry_password(entry_id.as_str()).await?.unwrap();
-    println!("{}", password);
+async fn pick(
+    client: PleasantPasswordServerClient,
+    query: &str,
+    copy: bool,
+    output: Option<OutputFormat>,
+) -> Result<()> {
+    let action = if copy { Action::Copy } else { Action::Print };
+    let (entry, field, action) = match picker::pick(&client, query, action)? {
+        Some(picked) => picked,
+        None => return Ok(()),
+    };
+    let value = match field {
+        Field::Password => client.entry_password(entry.id.as_str()).await?,
+        Field::Username => entry.username.clone(),
+        Field::Id => Some(entry.id.clone()),
+    };
+    let value = value
+        .ok_or_else(|| Error::NotFound(format!("Entry {} has no {}", entry.name, field.name())))?;
+    if action == Action::Copy {
+        picker::copy_to_clipboard(value.as_str())?;
+        eprintln!("Copied the {} of {}", field.name(), entry.name);
+    } else if field == Field::Password {
+        print_password(entry.id, Some(value), output)?
+    } else {
+        println!("{}", value);
+    }
     Ok(())
 }
+
+/// Answers `get-password` and `query` with a running agent. Returns false if there is none or
+/// the command is something else.
+fn ask_agent(
+    socket: &Path,
+    command: &Command,
+    password_output: Option<OutputFormat>,
+    output: Option<OutputFormat>,
+) -> Result<bool> {
+    let mut agent = match command {
+        Command::GetPassword { .. } | Command::Query { .. } => match AgentClient::connect(socket) {
+            Some(agent) => agent,
+            None => return Ok(false),
+        },
+        _ => return Ok(false),
+    };
+    match command {
+        Command::GetPassword { entry_id } => print_password(
+            entry_id.clone(),
+            agent.entry_password(entry_id.as_str())?,
+            password_output,
+        )?,
+        Command::Query { query, limit } => {
+            print_query(agent.query(query.as_str(), *limit)?, output)?
+        }
+        _ => {}
+    }
+    Ok(true)
+}
+
+async fn manage_agent(
+    client: PleasantPasswordServerClient,
+    socket: &Path,
+    command: AgentCommand,
+) -> Result<()> {
+    let request = match command {
+        AgentCommand::Start { idle_timeout } => {
+            return start_agent(client, socket, idle_timeout.as_str()).await
+        }
+        AgentCommand::Stop {} => AgentRequest::Stop,
+        AgentCommand::Lock {} => AgentRequest::Lock,
+        AgentCommand::Unlock {} => {
+            // asked before connecting, the agent drops connections which stay silent too long
+            let account = client.account();
+            let password = account.password.password(account.login.as_str())?;
+            AgentRequest::Unlock {
+                password: password.as_str().to_string(),
+                otp: match &account.otp {
+                    OtpSource::Code(code) => Some(code.clone()),
+                    _ => None,
+                },
+            }
+        }
+    };
+    AgentClient::connect(socket)
+        .ok_or_else(|| format!("No agent is running on {}", socket.display()))?
+        .request(&request)?;
+    Ok(())
+}
+
+async fn start_agent(
+    client: PleasantPasswordServerClient,
+    socket: &Path,
+    idle_timeout: &str,
+) -> Result<()> {
+    let idle_timeout = parse_ttl(idle_timeout).map_err(|_| {
+        format!(
+            "Invalid idle timeout '{}', expected never or a duration like 30m or 8h",
+            idle_timeout
+        )
+    })?;
+    let idle_timeout = idle_timeout.map(|seconds| Duration::from_secs(seconds as u64));
+    agent::check_idle_timeout(idle_timeout)?;
+    if agent::is_running(socket) {
+        return Err(format!("An agent is already running on {}", socket.display()).into());
+    }
+    // ask for the password now, while there is a terminal
+    client.log_in().await?;
+    eprintln!("Agent started on {}", socket.display());
+    agent::serve(client, socket, idle_timeout).await
+}
+
+/// Serves the keys until the process is stopped, e.g. with `kill $SSH_AGENT_PID`
+async fn start_ssh_agent(
+    client: PleasantPasswordServerClient,
+    socket: &Path,
+    query: Option<&str>,
+    folder: Option<&str>,
+) -> Result<()> {
+    let mut terms = Vec::new();
+    if let Some(folder) = folder {
+        terms.push(format!("path:{}", quote(folder)));
+    }
+    terms.extend(query.map(str::to_string));
+    // an empty query would match every entry
+    if terms.is_empty() {
+        return Err(Error::Other(
+            "Set PLEASANT_PASSWORD_SSH_FOLDER or PLEASANT_PASSWORD_SSH_QUERY to the entries holding the keys"
+                .to_string(),
+        ));
+    }
+    let query = terms.join(" ");
+    // fails early on a typo instead of with the first client
+    client.query(query.as_str(), Some(1))?;
+    if agent::is_running(socket) {
+        return Err(format!("An agent is already running on {}", socket.display()).into());
+    }
+    client.log_in().await?;
+    println!(
+        "SSH_AUTH_SOCK={}; export SSH_AUTH_SOCK;\nSSH_AGENT_PID={}; export SSH_AGENT_PID;",
+        socket.display(),
+        std::process::id()
+    );
+    ssh_agent::serve(client, socket, query.as_str()).await
+}
+
+/// Runs the command with the requested passwords in its environment and exits with its exit code
+async fn exec(
+    client: PleasantPasswordServerClient,
+    env: Vec<String>,
+    command: Vec<String>,
+) -> Result<()> {
+    let mut names = Vec::new();
+    let mut entry_ids = Vec::new();
+    for variable in env.iter() {
+        let (name, reference) = match variable.find('=') {
+            Some(index) if index > 0 => (&variable[..index], &variable[index + 1..]),
+            _ => {
+                return Err(
+                    format!("Expected NAME=<entry-id-or-query> but got {}", variable).into(),
+                )
+            }
+        };
+        names.push(name.to_string());
+        entry_ids.push(client.resolve_entry_id(reference)?);
+    }
+    let passwords = client.entry_passwords(entry_ids.as_slice()).await?;
+
+    let status = std::process::Command::new(&command[0])
+        .args(&command[1..])
+        .envs(names.into_iter().zip(passwords))
+        .status()
+        .map_err(|err| format!("Could not run {}: {}", command[0], err))?;
+    std::process::exit(status.code().unwrap_or(1));
+}
+
+/// Speaks git's credential helper protocol on stdin and stdout.
+///
+/// `get` answers with the username and password of the matching entry, or with nothing, so git
+/// asks the next helper. Git only sends `erase` after the password was rejected, so the cached
+/// password is dropped. Passwords are managed in Pleasant, so `store` does nothing.
+async fn git_credential(
+    client: PleasantPasswordServerClient,
+    operation: &str,
+    lookup: &CredentialLookup,
+) -> Result<()> {
+    let request = CredentialRequest::read(std::io::stdin().lock())?;
+    if operation != "get" && operation != "erase" {
+        return Ok(());
+    }
+    let entry = match git_credential::find_entry(&client, lookup, &request)? {
+        Some(entry) => entry,
+        None => {
+            info!("No entry matches {}", request);
+            return Ok(());
+        }
+    };
+    if operation == "erase" {
+        client.forget_password(entry.id.as_str())?;
+        return Ok(());
+    }
+
+    let password = client
+        .entry_password(entry.id.as_str())
+        .await?
+        .ok_or_else(|| Error::NotFound(format!("Entry {} has no password", entry.id)))?;
+    let username = entry
+        .username
+        .as_deref()
+        .or_else(|| request.get("username"));
+    write_credential(std::io::stdout(), username, password.as_str())
+}
+
+/// Speaks Docker's credential helper protocol on stdin and stdout.
+///
+/// `store` updates the password of the entry named after the registry or creates one named after
+/// its host in the configured folder. `erase`, run by `docker logout`, only forgets the cached
+/// password, the entry stays in Pleasant. `list` shows the entries of the configured folder.
+async fn docker_credential(
+    client: PleasantPasswordServerClient,
+    operation: &str,
+    lookup: &CredentialLookup,
+    folder: Option<&str>,
+) -> Result<()> {
+    match operation {
+        "get" => {
+            let server_url = docker_credential::read_server_url(std::io::stdin())?;
+            match docker_credential::get(&client, lookup, server_url).await? {
+                Some(credentials) => println!("{}", serde_json::to_string(&credentials)?),
+                None => {
+                    println!("{}", CREDENTIALS_NOT_FOUND);
+                    std::process::exit(EXIT_ERROR);
+                }
+            }
+        }
+        "list" => {
+            let entries = match folder {
+                Some(folder) => client.query(format!("path:{}", quote(folder)).as_str(), None)?,
+                None => Vec::new(),
+            };
+            // Docker expects server urls, other entries of the folder are no registries
+            let servers: serde_json::Map<String, serde_json::Value> = entries
+                .into_iter()
+                .filter(|entry| docker_credential::is_server_url(entry.name.as_str()))
+                .map(|entry| (entry.name, entry.username.unwrap_or_default().into()))
+                .collect();
+            println!("{}", serde_json::to_string(&servers)?);
+        }
+        "store" => {
+            let credentials = docker_credential::read_credentials(std::io::stdin())?;
+            docker_credential::store(&client, lookup, folder, credentials).await?
+        }
+        "erase" => {
+            let server_url = docker_credential::read_server_url(std::io::stdin())?;
+            docker_credential::erase(&client, lookup, server_url.as_str())?
+        }
+        _ => {
+            return Err(format!(
+                "Unknown operation {}, expected get, list, store or erase",
+                operation
+            )
+            .into())
+        }
+    }
+    Ok(())
+}
+
+fn manage_cache(
+    client: PleasantPasswordServerClient,
+    command: CacheCommand,
+    output: Option<OutputFormat>,
+) -> Result<()> {
+    match command {
+        CacheCommand::Clear {} => println!("Removed {} entries", client.clear_cache()?),
+        CacheCommand::PurgeExpired {} => {
+            println!("Removed {} expired entries", client.purge_expired_cache()?)
+        }
+        CacheCommand::List {} => write_records(
+            output.unwrap_or(OutputFormat::Table),
+            client.cache_entries()?.as_slice(),
+            std::io::stdout(),
+        )?,
+        CacheCommand::Forget { entry_id } => {
+            if !client.forget_password(entry_id.as_str())? {
+                eprintln!("No password of {} was cached", entry_id);
+            }
+        }
+    };
+    Ok(())
+}
+
+async fn manage_folder(client: PleasantPasswordServerClient, command: FolderCommand) -> Result<()> {
+    match command {
+        FolderCommand::Create { parent_id, name } => println!(
+            "{}",
+            client
+                .create_folder(name.as_str(), parent_id.as_str())
+                .await?
+        ),
+        FolderCommand::Rename { folder_id, name } => {
+            client
+                .rename_folder(folder_id.as_str(), name.as_str())
+                .await?
+        }
+        FolderCommand::Move {
+            folder_id,
+            parent_id,
+        } => {
+            client
+                .move_folder(folder_id.as_str(), parent_id.as_str())
+                .await?
+        }
+        FolderCommand::Delete { folder_id } => client.delete_folder(folder_id.as_str()).await?,
+    };
+    Ok(())
+}
+
+async fn manage_attachment(
+    client: PleasantPasswordServerClient,
+    command: AttachmentCommand,
+    output: Option<OutputFormat>,
+) -> Result<()> {
+    match command {
+        AttachmentCommand::List { entry_id } => {
+            let attachments = client.attachments(entry_id.as_str())?;
+            write_records(
+                output.unwrap_or(OutputFormat::Csv),
+                attachments.as_slice(),
+                std::io::stdout(),
+            )?
+        }
+        AttachmentCommand::Get {
+            entry_id,
+            attachment,
+            file,
+        } => {
+            let content = client
+                .download_attachment(entry_id.as_str(), attachment.as_str())
+                .await?;
+            match file {
+                Some(path) => write_private_file(path, content.as_slice())?,
+                None => std::io::stdout().write_all(content.as_slice())?,
+            }
+        }
+        AttachmentCommand::Put {
+            entry_id,
+            path,
+            name,
+        } => {
+            let file_name = match name {
+                Some(name) => name,
+                None => path
+                    .file_name()
+                    .ok_or("The path has no file name")?
+                    .to_string_lossy()
+                    .to_string(),
+            };
+            let content = std::fs::read(&path)?;
+            let attachment_id = client
+                .upload_attachment(entry_id.as_str(), file_name.as_str(), content.as_slice())
+                .await?;
+            println!("{}", attachment_id);
+        }
+    };
+    Ok(())
+}
+
+/// Loads the settings, flags win over the environment, which wins over the profile.
+///
+/// Fails with all problems, not just the first one.
+fn load_settings(args: &Args) -> std::result::Result<Settings, SettingErrors> {
+    let mut validation = Validation::default();
+
+    let config_file = match validation.check(optional_string("PLEASANT_PASSWORD_CONFIG")) {
+        Some(Some(path)) => Some(PathBuf::from(path)),
+        Some(None) => validation.check(data_dir(None).map(|dir| dir.join("config.toml")).map_err(
+            |err| SettingError::Invalid {
+                name: "PLEASANT_PASSWORD_CONFIG".to_string(),
+                reason: format!(
+                    "it is not set and the data directory can not be used: {}",
+                    err
+                ),
+            },
+        )),
+        None => None,
+    };
+    let profile_name = match &args.profile {
+        Some(name) => Some(name.clone()),
+        None => validation
+            .check(optional_string("PLEASANT_PASSWORD_PROFILE"))
+            .flatten(),
+    };
+    let profile = config_file
+        .and_then(|path| {
+            validation.check(
+                ConfigFile::load(&path)
+                    .and_then(|config| config.profile(profile_name.as_deref()))
+                    .map_err(|err| SettingError::Invalid {
+                        name: path.display().to_string(),
+                        reason: err.to_string(),
+                    }),
+            )
+        })
+        .flatten();
+    let (profile_name, profile) = match profile {
+        Some((name, profile)) => (Some(name), profile),
+        None => (None, Profile::default()),
+    };
+    let tls = tls_options(&mut validation, &profile);
+
+    let url = url_setting(
+        &mut validation,
+        "PLEASANT_PASSWORD_SERVER_URL",
+        "profile url",
+        profile.url,
+    );
+    let url = validation.require("PLEASANT_PASSWORD_SERVER_URL", url);
+    let http_proxy = url_setting(
+        &mut validation,
+        "HTTP_PROXY",
+        "profile http_proxy",
+        profile.http_proxy,
+    )
+    .flatten();
+    let https_proxy = url_setting(
+        &mut validation,
+        "HTTPS_PROXY",
+        "profile https_proxy",
+        profile.https_proxy,
+    )
+    .flatten();
+    let profile_login = profile.login;
+    let login = validation
+        .check(optional_string("PLEASANT_PASSWORD_SERVER_LOGIN"))
+        .map(|login| login.or(profile_login));
+    let login = validation.require("PLEASANT_PASSWORD_SERVER_LOGIN", login);
+    let password = validation
+        .check(optional_secure_string("PLEASANT_PASSWORD_SERVER_PASSWORD"))
+        .flatten();
+    let password_command = validation
+        .check(optional_string("PLEASANT_PASSWORD_SERVER_PASSWORD_COMMAND"))
+        .flatten()
+        .or(profile.password_command);
+    let totp_secret = validation
+        .check(optional_secure_string(
+            "PLEASANT_PASSWORD_SERVER_TOTP_SECRET",
+        ))
+        .flatten();
+    let cache_secret = validation
+        .check(optional_secure_string("PLEASANT_PASSWORD_CACHE_SECRET"))
+        .flatten();
+    let cache_key_file = validation
+        .check(optional_string("PLEASANT_PASSWORD_CACHE_KEY_FILE"))
+        .flatten()
+        .or(profile.cache_key_file);
+
+    let password_ttl = match validation
+        .check(optional_string("PLEASANT_PASSWORD_CACHE_TTL"))
+        .flatten()
+        .or(profile.cache_ttl)
+    {
+        Some(ttl) => validation
+            .check(
+                parse_ttl(ttl.as_str()).map_err(|reason| SettingError::Invalid {
+                    name: "PLEASANT_PASSWORD_CACHE_TTL".to_string(),
+                    reason,
+                }),
+            )
+            .flatten(),
+        None => Some(DEFAULT_PASSWORD_TTL),
+    };
+    let cache_rules = match validation
+        .check(optional_string("PLEASANT_PASSWORD_CACHE_RULES"))
+        .flatten()
+    {
+        Some(rules) => CachePolicy::parse_rules(rules.as_str()),
+        None => profile
+            .cache_rules
+            .iter()
+            .map(|rule| rule.parse())
+            .collect(),
+    };
+    let cache_rules = validation
+        .check(cache_rules.map_err(|reason| SettingError::Invalid {
+            name: "PLEASANT_PASSWORD_CACHE_RULES".to_string(),
+            reason,
+        }))
+        .unwrap_or_default();
+
+    let output = match (args.output, profile.output) {
+        (Some(output), _) => Some(output),
+        (None, Some(output)) => {
+            validation.check(output.parse::<OutputFormat>().map_err(|reason| {
+                SettingError::Invalid {
+                    name: "profile output".to_string(),
+                    reason,
+                }
+            }))
+        }
+        (None, None) => None,
+    };
+
+    let git_credential_lookup = lookup_setting(
+        &mut validation,
+        "PLEASANT_PASSWORD_GIT_CREDENTIAL_LOOKUP",
+        profile.git_credential_lookup,
+        git_credential::DEFAULT_LOOKUP,
+    );
+    let docker_credential_lookup = lookup_setting(
+        &mut validation,
+        "PLEASANT_PASSWORD_DOCKER_CREDENTIAL_LOOKUP",
+        profile.docker_credential_lookup,
+        docker_credential::DEFAULT_LOOKUP,
+    );
+    let docker_folder = validation
+        .check(optional_string("PLEASANT_PASSWORD_DOCKER_FOLDER"))
+        .flatten()
+        .or(profile.docker_folder);
+    let ssh_query = validation
+        .check(optional_string("PLEASANT_PASSWORD_SSH_QUERY"))
+        .flatten()
+        .or(profile.ssh_query);
+    let ssh_folder = validation
+        .check(optional_string("PLEASANT_PASSWORD_SSH_FOLDER"))
+        .flatten()
+        .or(profile.ssh_folder);
+
+    let (url, login) = validation.finish(url.zip(login))?;
+    Ok(Settings {
+        profile_name,
+        url,
+        http_proxy,
+        https_proxy,
+        login,
+        password,
+        password_command,
+        totp_secret,
+        cache_secret,
+        cache_key_file,
+        password_ttl,
+        cache_rules,
+        output,
+        tls,
+        git_credential_lookup,
+        docker_credential_lookup,
+        docker_folder,
+        ssh_query,
+        ssh_folder,
+    })
+}
+
+/// Query templates of a credential helper from the environment, the profile or the default
+fn lookup_setting(
+    validation: &mut Validation,
+    setting_name: &str,
+    profile_value: Vec<String>,
+    default: &str,
+) -> CredentialLookup {
+    let lookup = match validation.check(optional_string(setting_name)).flatten() {
+        Some(lookup) => CredentialLookup::parse(lookup.as_str()),
+        None if profile_value.is_empty() => CredentialLookup::parse(default),
+        None => CredentialLookup::new(profile_value),
+    };
+    validation
+        .check(lookup.map_err(|reason| SettingError::Invalid {
+            name: setting_name.to_string(),
+            reason,
+        }))
+        .unwrap_or_default()
+}
+
+/// The TLS settings. A client certificate with a key file is PEM, without one PKCS#12.
+fn tls_options(validation: &mut Validation, profile: &Profile) -> TlsOptions {
+    let mut path_setting = |setting_name: &str, profile_value: &Option<String>| {
+        validation
+            .check(optional_string(setting_name))
+            .flatten()
+            .or_else(|| profile_value.clone())
+            .map(PathBuf::from)
+    };
+    let ca_bundle = path_setting("PLEASANT_PASSWORD_TLS_CA_BUNDLE", &profile.tls_ca_bundle);
+    let client_cert = path_setting(
+        "PLEASANT_PASSWORD_TLS_CLIENT_CERT",
+        &profile.tls_client_cert,
+    );
+    let client_key = path_setting("PLEASANT_PASSWORD_TLS_CLIENT_KEY", &profile.tls_client_key);
+    let client_cert_password = validation
+        .check(optional_secure_string(
+            "PLEASANT_PASSWORD_TLS_CLIENT_CERT_PASSWORD",
+        ))
+        .flatten();
+
+    let client_certificate = match (client_cert, client_key) {
+        (Some(certificate), Some(key)) => Some(ClientCertificate::Pem { certificate, key }),
+        (Some(file), None) => Some(ClientCertificate::Pkcs12 {
+            file,
+            password: client_cert_password
+                .map(|password| password.as_str().to_string())
+                .unwrap_or_default(),
+        }),
+        (None, Some(_)) => {
+            validation.check::<()>(Err(SettingError::Invalid {
+                name: "PLEASANT_PASSWORD_TLS_CLIENT_KEY".to_string(),
+                reason: "a client key needs PLEASANT_PASSWORD_TLS_CLIENT_CERT".to_string(),
+            }));
+            None
+        }
+        (None, None) => None,
+    };
+
+    let min_version = validation
+        .check(optional_string("PLEASANT_PASSWORD_TLS_MIN_VERSION"))
+        .flatten()
+        .or_else(|| profile.tls_min_version.clone())
+        .and_then(|version| {
+            validation.check(version.parse::<TlsVersion>().map_err(|reason| {
+                SettingError::Invalid {
+                    name: "PLEASANT_PASSWORD_TLS_MIN_VERSION".to_string(),
+                    reason,
+                }
+            }))
+        });
+
+    let pinned_keys: Vec<String> = match validation
+        .check(optional_string("PLEASANT_PASSWORD_TLS_PINNED_KEYS"))
+        .flatten()
+    {
+        Some(pins) => pins
+            .split(',')
+            .map(str::trim)
+            .filter(|pin| !pin.is_empty())
+            .map(str::to_string)
+            .collect(),
+        None => profile.tls_pinned_keys.clone(),
+    };
+    let pinned_keys = validation
+        .check(
+            pinned_keys
+                .iter()
+                .map(|pin| tls::parse_pin(pin))
+                .collect::<std::result::Result<Vec<String>, String>>()
+                .map_err(|reason| SettingError::Invalid {
+                    name: "PLEASANT_PASSWORD_TLS_PINNED_KEYS".to_string(),
+                    reason,
+                }),
+        )
+        .unwrap_or_default();
+
+    TlsOptions {
+        ca_bundle,
+        client_certificate,
+        min_version,
+        pinned_keys,
+    }
+}
+
+/// An url from the environment or else from the profile. Returns `None` if one of them is
+/// invalid and `Some(None)` if neither is present.
+fn url_setting(
+    validation: &mut Validation,
+    setting_name: &str,
+    profile_setting_name: &str,
+    profile_value: Option<String>,
+) -> Option<Option<Url>> {
+    let url = validation.check(optional_url(setting_name));
+    let profile_url = match profile_value {
+        Some(value) => validation
+            .check(parse_url(profile_setting_name, value.as_str()))
+            .map(Some),
+        None => Some(None),
+    };
+    match (url, profile_url) {
+        (Some(Some(url)), _) => Some(Some(url)),
+        (Some(None), profile_url) => profile_url,
+        (None, _) => None,
+    }
+}
+
+/// The command line arguments. Run as `docker-credential-pleasant <operation>`, e.g. through a
+/// symlink, the `docker-credential` subcommand is implied.
+fn arguments() -> Vec<OsString> {
+    let mut arguments: Vec<OsString> = std::env::args_os().collect();
+    let invoked_as_docker_helper = arguments
+        .first()
+        .and_then(|program| Path::new(program).file_name())
+        .map(|name| name == docker_credential::HELPER_NAME)
+        .unwrap_or(false);
+    if invoked_as_docker_helper {
+        arguments.insert(1, OsString::from("docker-credential"));
+    }
+    arguments
+}
+
+/// Returns the argument itself or, if it is `-`, the first line of stdin
+fn read_password_arg(password: String) -> Result<String> {
+    if password != "-" {
+        return Ok(password);
+    }
+    read_line_stdin()
+}
+
+fn read_password_stdin() -> Result<SecureString> {
+    let password = read_line_stdin()?;
+    if password.is_empty() {
+        return Err("--password-stdin was given but stdin contains no password".into());
+    }
+    Ok(SecureString::from(password))
+}
+
+fn read_line_stdin() -> Result<String> {
+    let mut line = String::new();
+    std::io::stdin().lock().read_line(&mut line)?;
+    Ok(line.trim_end_matches(&['\r', '\n'][..]).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prefers_the_url_of_the_environment() {
+        std::env::set_var("PLEASANT_PASSWORD_TEST_MAIN_URL", "https://env.example.com");
+        std::env::remove_var("PLEASANT_PASSWORD_TEST_MAIN_UNSET");
+        let mut validation = Validation::default();
+        let profile_url = Some("https://profile.example.com".to_string());
+
+        let url = url_setting(
+            &mut validation,
+            "PLEASANT_PASSWORD_TEST_MAIN_URL",
+            "profile url",
+            profile_url.clone(),
+        );
+        assert_eq!(url.flatten().unwrap().host_str(), Some("env.example.com"));
+        let url = url_setting(
+            &mut validation,
+            "PLEASANT_PASSWORD_TEST_MAIN_UNSET",
+            "profile url",
+            profile_url,
+        );
+        assert_eq!(
+            url.flatten().unwrap().host_str(),
+            Some("profile.example.com")
+        );
+        let url = url_setting(
+            &mut validation,
+            "PLEASANT_PASSWORD_TEST_MAIN_UNSET",
+            "profile url",
+            None,
+        );
+        assert!(matches!(url, Some(None)));
+        assert!(validation.finish(Some(())).is_ok());
+    }
+
+    #[test]
+    fn reports_an_invalid_profile_url() {
+        std::env::remove_var("PLEASANT_PASSWORD_TEST_MAIN_UNSET");
+        let mut validation = Validation::default();
+        let url = url_setting(
+            &mut validation,
+            "PLEASANT_PASSWORD_TEST_MAIN_UNSET",
+            "profile url",
+            Some("pleasant".to_string()),
+        );
+        assert!(url.is_none());
+        assert_eq!(
+            validation.finish(Some(())).unwrap_err().to_string(),
+            "Setting profile url is not a valid url: relative URL without a base. Value: pleasant"
+        );
+    }
+
+    #[test]
+    fn prefers_the_lookup_of_the_environment() {
+        std::env::set_var("PLEASANT_PASSWORD_TEST_MAIN_LOOKUP", "name:{host}; ");
+        std::env::remove_var("PLEASANT_PASSWORD_TEST_MAIN_UNSET");
+        let request = CredentialRequest::from_url("https://registry.example.com");
+        let mut validation = Validation::default();
+
+        let lookup = lookup_setting(
+            &mut validation,
+            "PLEASANT_PASSWORD_TEST_MAIN_UNSET",
+            Vec::new(),
+            "host:{host}",
+        );
+        assert_eq!(
+            lookup.queries(&request),
+            vec!["host:\"registry.example.com\""]
+        );
+        let lookup = lookup_setting(
+            &mut validation,
+            "PLEASANT_PASSWORD_TEST_MAIN_LOOKUP",
+            vec!["path:Root".to_string(), "name:{host}".to_string()],
+            "host:{host}",
+        );
+        assert_eq!(
+            lookup.queries(&request),
+            vec!["name:\"registry.example.com\""]
+        );
+        assert!(validation.finish(Some(())).is_ok());
+    }
+}
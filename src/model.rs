@@ -1,21 +1,54 @@
 use crate::db::db_types::{Attachment, CredentialEntry, Folder};
+use crate::query::Query;
 use crate::types::*;
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use log::*;
-use rusqlite::{params, Connection};
-use serde::Serialize;
+use rusqlite::{params, Connection, Rows, ToSql};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter, Result as FmtResult};
 
 pub struct PleasantPasswordModel {
     connection: Connection,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct Credentials {
-    id: String,
-    folder_name: String,
-    name: String,
-    username: Option<String>,
-    notes: Option<String>,
+    pub id: String,
+    pub folder_name: String,
+    /// full path of the folder, e.g. Root/Team/Prod/Database
+    pub folder_path: String,
+    pub name: String,
+    pub username: Option<String>,
+    pub notes: Option<String>,
+}
+
+/// Number of credentials entries touched by a sync
+#[derive(Debug, Default)]
+pub struct SyncReport {
+    pub added: usize,
+    pub changed: usize,
+    pub removed: usize,
+}
+
+impl Display for SyncReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(
+            f,
+            "{} added, {} changed, {} removed",
+            self.added, self.changed, self.removed
+        )
+    }
+}
+
+#[derive(Default)]
+struct SyncState {
+    known_folders: HashMap<String, DateTime<Utc>>,
+    known_credentials: HashMap<String, DateTime<Utc>>,
+    seen_folders: HashSet<String>,
+    seen_credentials: HashSet<String>,
+    seen_attachments: HashSet<String>,
+    report: SyncReport,
 }
 
 impl PleasantPasswordModel {
@@ -25,31 +58,82 @@ impl PleasantPasswordModel {
         Ok(model)
     }
 
-    pub fn query_for_credentials(&self, query: &str) -> Result<Vec<Credentials>> {
+    /// Searches the credentials with a query, see [`Query`] for the syntax.
+    ///
+    /// Free text terms have to match the beginning of a word in the name, username, notes or
+    /// folder path and rank the results by relevance. Hits in the name weigh more than hits in
+    /// the notes. Without free text terms the results are ordered by name.
+    pub fn query_for_credentials(
+        &self,
+        query: &str,
+        limit: Option<u32>,
+    ) -> Result<Vec<Credentials>> {
+        let query = Query::parse(query)?;
+        let match_expression = query.match_expression();
+        let filter = query.sql_filter();
+
+        let mut sql = String::from(
+            r#"
+SELECT c.id, f.name, COALESCE(p.path, f.name), c.name, c.username, c.notes FROM credentials c
+INNER JOIN folders f on c.group_id = f.id
+LEFT JOIN folder_paths p on c.group_id = p.id
+"#,
+        );
+        let mut conditions: Vec<String> = Vec::new();
+        let mut params: Vec<Box<dyn ToSql>> = Vec::new();
+        if !match_expression.is_empty() {
+            sql.push_str("INNER JOIN credentials_fts on c.id = credentials_fts.id\n");
+            conditions.push("credentials_fts MATCH ?".to_string());
+            params.push(Box::new(match_expression.clone()));
+        }
+        conditions.extend(filter.conditions);
+        params.extend(filter.params);
+
+        if !conditions.is_empty() {
+            sql.push_str(format!("WHERE {}\n", conditions.join("\nAND ")).as_str());
+        }
+        if match_expression.is_empty() {
+            sql.push_str("ORDER BY c.name\n");
+        } else {
+            sql.push_str("ORDER BY bm25(credentials_fts, 0.0, 10.0, 5.0, 1.0, 2.0)\n");
+        }
+        sql.push_str("LIMIT ?");
+        params.push(Box::new(limit.map(i64::from).unwrap_or(-1)));
+
+        debug!("Query: {}", sql);
+        let mut stmt = self.connection.prepare(sql.as_str())?;
+        let rows = stmt.query(params.iter())?;
+        PleasantPasswordModel::read_credentials(rows)
+    }
+
+    /// The entry with this id, `None` if it is not in the local database
+    pub fn credentials_by_id(&self, id: &str) -> Result<Option<Credentials>> {
         let mut stmt = self.connection.prepare(
             r#"
-SELECT c.id, f.name, c.name, c.username, c.notes FROM credentials c
+SELECT c.id, f.name, COALESCE(p.path, f.name), c.name, c.username, c.notes FROM credentials c
 INNER JOIN folders f on c.group_id = f.id
-WHERE f.name like '%' || :query || '%'
-OR c.name like '%' || :query || '%'
-OR c.username like '%' || :query || '%'
-OR c.notes like '%' || :query || '%'
+LEFT JOIN folder_paths p on c.group_id = p.id
+WHERE c.id = ?1
 "#,
         )?;
+        let rows = stmt.query(params![id])?;
+        Ok(PleasantPasswordModel::read_credentials(rows)?.pop())
+    }
 
-        let mut rows = stmt.query_named(&[(":query", &query)])?;
-
+    fn read_credentials(mut rows: Rows) -> Result<Vec<Credentials>> {
         let mut result: Vec<Credentials> = Vec::new();
         while let Some(row) = rows.next()? {
             let id: String = row.get(0)?;
             let folder_name: String = row.get(1)?;
-            let name: String = row.get(2)?;
-            let username: Option<String> = row.get(3)?;
-            let notes: Option<String> = row.get(4)?;
+            let folder_path: String = row.get(2)?;
+            let name: String = row.get(3)?;
+            let username: Option<String> = row.get(4)?;
+            let notes: Option<String> = row.get(5)?;
 
             result.push(Credentials {
                 id,
                 folder_name,
+                folder_path,
                 name,
                 username,
                 notes,
@@ -59,49 +143,257 @@ OR c.notes like '%' || :query || '%'
         Ok(result)
     }
 
-    pub fn add_root_folder(&self, folder: Folder) -> Result<()> {
-        debug!("Add root folder. Truncating tables");
-        self.connection.execute_batch(
+    pub fn attachments(&self, credentials_id: &str) -> Result<Vec<Attachment>> {
+        let mut stmt = self.connection.prepare(
             r#"
-DELETE FROM credentials;
-DELETE FROM folders;
-DELETE FROM attachments;
-        "#,
+SELECT credentials_id, id, file_name, file_size FROM attachments
+WHERE credentials_id = ?1
+ORDER BY file_name
+"#,
         )?;
 
-        self.add_folder(folder)
+        let mut rows = stmt.query(params![credentials_id])?;
+
+        let mut result: Vec<Attachment> = Vec::new();
+        while let Some(row) = rows.next()? {
+            result.push(Attachment {
+                credential_object_id: row.get(0)?,
+                attachment_id: row.get(1)?,
+                file_name: row.get(2)?,
+                file_size: row.get(3)?,
+            });
+        }
+
+        Ok(result)
     }
 
-    fn add_folder(&self, folder: Folder) -> Result<()> {
-        debug!("Add folder {}", &folder.name);
+    /// The full paths of all folders, e.g. Root/Team/Prod, sorted
+    pub fn folder_paths(&self) -> Result<Vec<String>> {
+        let mut stmt = self
+            .connection
+            .prepare("SELECT path FROM folder_paths ORDER BY path")?;
+        let mut rows = stmt.query(params![])?;
+        let mut result: Vec<String> = Vec::new();
+        while let Some(row) = rows.next()? {
+            result.push(row.get(0)?);
+        }
+        Ok(result)
+    }
 
-        let statement = r#"
-INSERT INTO folders (id, parent_id, name, created, modified, expires, synced) 
-VALUES (?1,?2,?3,?4,?5,?6,?7)
-"#;
-        let id = &folder.id;
-        let parent_id = &folder.parent_id;
-        let name = &folder.name;
-        let created = &folder.created;
-        let modified = &folder.modified;
-        let expires = &folder.expires;
-        let synced = Utc::now();
+    /// The id of the folder with this full path, e.g. Root/Team/Prod, ignoring case
+    pub fn folder_id(&self, path: &str) -> Result<Option<String>> {
+        let mut stmt = self
+            .connection
+            .prepare("SELECT id FROM folder_paths WHERE lower(path) = lower(?1)")?;
+        let mut rows = stmt.query(params![path.trim_end_matches('/')])?;
+        match rows.next()? {
+            Some(row) => Ok(Some(row.get(0)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Synchronizes the local database with the folder tree of the server.
+    ///
+    /// Only rows which are new or have a different modification date are written and rows which
+    /// are not part of the tree anymore are removed. Everything happens in a single transaction,
+    /// so a failed sync leaves the database as it was.
+    pub fn sync_root_folder(&self, root_folder: Folder) -> Result<SyncReport> {
+        debug!("Sync root folder {}", &root_folder.name);
+        let transaction = self.connection.unchecked_transaction()?;
+
+        let mut state = SyncState {
+            known_folders: self.modification_dates("folders")?,
+            known_credentials: self.modification_dates("credentials")?,
+            ..Default::default()
+        };
+        self.sync_folder(root_folder, &mut state)?;
+
+        for id in state.known_folders.keys() {
+            if !state.seen_folders.contains(id) {
+                debug!("Remove folder {}", id);
+                self.connection
+                    .execute("DELETE FROM folders WHERE id = ?1", params![id])?;
+            }
+        }
+        for id in state.known_credentials.keys() {
+            if !state.seen_credentials.contains(id) {
+                debug!("Remove credentials entry {}", id);
+                self.connection
+                    .execute("DELETE FROM credentials WHERE id = ?1", params![id])?;
+                state.report.removed += 1;
+            }
+        }
+        for id in self.ids("attachments")? {
+            if !state.seen_attachments.contains(&id) {
+                debug!("Remove attachment {}", id);
+                self.connection
+                    .execute("DELETE FROM attachments WHERE id = ?1", params![id])?;
+            }
+        }
+
+        self.reindex()?;
+        transaction.commit()?;
+        Ok(state.report)
+    }
+
+    /// Inserts or replaces a single credentials entry including its attachments in a transaction
+    pub fn save_credentials(&self, credential: CredentialEntry) -> Result<()> {
+        debug!("Save credentials entry {}", &credential.name);
+        let transaction = self.connection.unchecked_transaction()?;
         self.connection.execute(
-            statement,
-            params![id, parent_id, name, created, modified, expires, synced],
+            "DELETE FROM attachments WHERE credentials_id = ?1",
+            params![&credential.id],
         )?;
+        self.connection.execute(
+            "DELETE FROM credentials WHERE id = ?1",
+            params![&credential.id],
+        )?;
+        self.add_credentials(credential)?;
+        self.reindex()?;
+        transaction.commit()?;
+        Ok(())
+    }
+
+    pub fn delete_credentials(&self, id: &str) -> Result<()> {
+        debug!("Delete credentials entry {}", id);
+        let transaction = self.connection.unchecked_transaction()?;
+        self.connection.execute(
+            "DELETE FROM attachments WHERE credentials_id = ?1",
+            params![id],
+        )?;
+        self.connection
+            .execute("DELETE FROM credentials WHERE id = ?1", params![id])?;
+        self.reindex()?;
+        transaction.commit()?;
+        Ok(())
+    }
+
+    /// Inserts or replaces a single folder row. Children and credentials are left untouched.
+    pub fn save_folder(&self, folder: &Folder) -> Result<()> {
+        self.add_folder(folder)?;
+        self.reindex()
+    }
 
-        for cred in folder.credentials.into_iter() {
-            self.add_credentials(cred)?;
+    /// Deletes a folder together with all sub folders, their credentials and attachments, in a
+    /// single transaction
+    pub fn delete_folder(&self, id: &str) -> Result<()> {
+        debug!("Delete folder {}", id);
+        let transaction = self.connection.unchecked_transaction()?;
+        self.connection.execute_named(
+            r#"
+WITH RECURSIVE subtree(id) AS (
+    SELECT :id
+    UNION ALL
+    SELECT f.id FROM folders f INNER JOIN subtree s ON f.parent_id = s.id
+)
+DELETE FROM attachments WHERE credentials_id IN (
+    SELECT c.id FROM credentials c WHERE c.group_id IN subtree
+)
+"#,
+            &[(":id", &id)],
+        )?;
+        self.connection.execute_named(
+            r#"
+WITH RECURSIVE subtree(id) AS (
+    SELECT :id
+    UNION ALL
+    SELECT f.id FROM folders f INNER JOIN subtree s ON f.parent_id = s.id
+)
+DELETE FROM credentials WHERE group_id IN subtree
+"#,
+            &[(":id", &id)],
+        )?;
+        self.connection.execute_named(
+            r#"
+WITH RECURSIVE subtree(id) AS (
+    SELECT :id
+    UNION ALL
+    SELECT f.id FROM folders f INNER JOIN subtree s ON f.parent_id = s.id
+)
+DELETE FROM folders WHERE id IN subtree
+"#,
+            &[(":id", &id)],
+        )?;
+        self.reindex()?;
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn sync_folder(&self, folder: Folder, state: &mut SyncState) -> Result<()> {
+        match state.known_folders.get(&folder.id) {
+            Some(modified) if *modified == folder.modified => {}
+            _ => self.add_folder(&folder)?,
+        }
+        state.seen_folders.insert(folder.id.clone());
+
+        for credential in folder.credentials.into_iter() {
+            state.seen_credentials.insert(credential.id.clone());
+            for attachment in credential.attachments.iter() {
+                state
+                    .seen_attachments
+                    .insert(attachment.attachment_id.clone());
+            }
+            match state.known_credentials.get(&credential.id) {
+                None => state.report.added += 1,
+                Some(modified) if *modified != credential.modified => state.report.changed += 1,
+                Some(_) => continue,
+            }
+            self.add_credentials(credential)?;
         }
 
         for child_folder in folder.children.into_iter() {
-            self.add_folder(child_folder)?;
+            self.sync_folder(child_folder, state)?;
         }
 
         Ok(())
     }
 
+    fn modification_dates(&self, table: &str) -> Result<HashMap<String, DateTime<Utc>>> {
+        let mut stmt = self
+            .connection
+            .prepare(format!("SELECT id, modified FROM {}", table).as_str())?;
+        let mut rows = stmt.query(params![])?;
+
+        let mut result = HashMap::new();
+        while let Some(row) = rows.next()? {
+            result.insert(row.get(0)?, row.get(1)?);
+        }
+        Ok(result)
+    }
+
+    fn ids(&self, table: &str) -> Result<Vec<String>> {
+        let mut stmt = self
+            .connection
+            .prepare(format!("SELECT id FROM {}", table).as_str())?;
+        let mut rows = stmt.query(params![])?;
+
+        let mut result = Vec::new();
+        while let Some(row) = rows.next()? {
+            result.push(row.get(0)?);
+        }
+        Ok(result)
+    }
+
+    fn add_folder(&self, folder: &Folder) -> Result<()> {
+        debug!("Add folder {}", &folder.name);
+        self.connection.execute(
+            r#"
+INSERT OR REPLACE INTO folders (id, parent_id, name, created, modified, expires, synced)
+VALUES (?1,?2,?3,?4,?5,?6,?7)
+"#,
+            params![
+                &folder.id,
+                &folder.parent_id,
+                &folder.name,
+                &folder.created,
+                &folder.modified,
+                &folder.expires,
+                Utc::now()
+            ],
+        )?;
+        Ok(())
+    }
+
     fn add_credentials(&self, credential: CredentialEntry) -> Result<()> {
         debug!("Add credentials entry {}", &credential.name);
         let id = &credential.id;
@@ -115,7 +407,7 @@ VALUES (?1,?2,?3,?4,?5,?6,?7)
         let synced = Utc::now();
         self.connection.execute(
             r#"
-INSERT INTO credentials (id, name, username, notes, group_id, created, modified, expires, synced) 
+INSERT OR REPLACE INTO credentials (id, name, username, notes, group_id, created, modified, expires, synced)
 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)
 "#,
             params![id, name, username, notes, group_id, created, modified, expires, synced],
@@ -131,7 +423,7 @@ VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)
     fn add_attachment(&self, attachment: Attachment) -> Result<()> {
         debug!("Add attachment into database");
         let statement = r#"
-INSERT INTO attachments (id, credentials_id, file_name, file_size) 
+INSERT OR REPLACE INTO attachments (id, credentials_id, file_name, file_size)
 VALUES (?1,?2,?3,?4)
 "#;
         let id = &attachment.attachment_id;
@@ -145,6 +437,20 @@ VALUES (?1,?2,?3,?4)
         Ok(())
     }
 
+    /// Rebuilds the full text index from the credentials and folders tables
+    fn reindex(&self) -> Result<()> {
+        debug!("Rebuild full text index");
+        self.connection.execute_batch(
+            r#"
+DELETE FROM credentials_fts;
+INSERT INTO credentials_fts (id, name, username, notes, folder_path)
+SELECT c.id, c.name, c.username, c.notes, p.path FROM credentials c
+LEFT JOIN folder_paths p ON c.group_id = p.id;
+"#,
+        )?;
+        Ok(())
+    }
+
     fn init_db(&self) -> Result<()> {
         debug!("Initialize credentials database");
         let sql_statement = include_str!("../assets/sql/init_db.sql");
@@ -152,3 +458,202 @@ VALUES (?1,?2,?3,?4)
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn model() -> PleasantPasswordModel {
+        PleasantPasswordModel::new(Connection::open_in_memory().unwrap()).unwrap()
+    }
+
+    fn date(day: u32) -> DateTime<Utc> {
+        Utc.ymd(2020, 1, day).and_hms(0, 0, 0)
+    }
+
+    fn entry(id: &str, name: &str, modified: u32, attachments: &[&str]) -> CredentialEntry {
+        CredentialEntry {
+            id: id.to_string(),
+            name: name.to_string(),
+            username: None,
+            attachments: attachments
+                .iter()
+                .map(|attachment_id| Attachment {
+                    credential_object_id: id.to_string(),
+                    attachment_id: attachment_id.to_string(),
+                    file_name: format!("{}.pem", attachment_id),
+                    file_size: 5,
+                })
+                .collect(),
+            notes: None,
+            // set by `folder`
+            group_id: String::new(),
+            created: date(1),
+            modified: date(modified),
+            expires: None,
+            synced: None,
+        }
+    }
+
+    fn folder(
+        id: &str,
+        name: &str,
+        credentials: Vec<CredentialEntry>,
+        children: Vec<Folder>,
+    ) -> Folder {
+        Folder {
+            credentials: credentials
+                .into_iter()
+                .map(|credentials| CredentialEntry {
+                    group_id: id.to_string(),
+                    ..credentials
+                })
+                .collect(),
+            children: children
+                .into_iter()
+                .map(|child| Folder {
+                    parent_id: id.to_string(),
+                    ..child
+                })
+                .collect(),
+            id: id.to_string(),
+            name: name.to_string(),
+            parent_id: "00000000".to_string(),
+            created: date(1),
+            modified: date(1),
+            expires: None,
+            synced: None,
+        }
+    }
+
+    /// Root with Prod DB and Staging, Team with Deploy and Old with Legacy
+    fn first_tree() -> Folder {
+        folder(
+            "root",
+            "Root",
+            vec![
+                entry("prod", "Prod DB", 1, &["prod-1", "prod-2"]),
+                entry("staging", "Staging", 1, &[]),
+            ],
+            vec![
+                folder(
+                    "team",
+                    "Team",
+                    vec![entry("deploy", "Deploy", 1, &[])],
+                    vec![],
+                ),
+                folder(
+                    "old",
+                    "Old",
+                    vec![entry("legacy", "Legacy", 1, &["legacy-1"])],
+                    vec![],
+                ),
+            ],
+        )
+    }
+
+    /// Prod DB lost an attachment, Old is gone and Root got Backup
+    fn second_tree() -> Folder {
+        folder(
+            "root",
+            "Root",
+            vec![
+                entry("prod", "Prod DB", 2, &["prod-1"]),
+                entry("staging", "Staging", 1, &[]),
+                entry("backup", "Backup", 2, &[]),
+            ],
+            vec![folder(
+                "team",
+                "Team",
+                vec![entry("deploy", "Deploy", 1, &[])],
+                vec![],
+            )],
+        )
+    }
+
+    fn attachment_ids(model: &PleasantPasswordModel, credentials_id: &str) -> Vec<String> {
+        model
+            .attachments(credentials_id)
+            .unwrap()
+            .into_iter()
+            .map(|attachment| attachment.attachment_id)
+            .collect()
+    }
+
+    fn names(model: &PleasantPasswordModel) -> Vec<String> {
+        model
+            .query_for_credentials("path:Root", None)
+            .unwrap()
+            .into_iter()
+            .map(|credentials| credentials.name)
+            .collect()
+    }
+
+    #[test]
+    fn syncs_the_changes_of_the_tree() {
+        let model = model();
+        let report = model.sync_root_folder(first_tree()).unwrap();
+        assert_eq!(report.to_string(), "4 added, 0 changed, 0 removed");
+        assert_eq!(
+            model.folder_paths().unwrap(),
+            vec!["Root", "Root/Old", "Root/Team"]
+        );
+        assert_eq!(attachment_ids(&model, "prod"), vec!["prod-1", "prod-2"]);
+
+        let report = model.sync_root_folder(second_tree()).unwrap();
+        assert_eq!(report.to_string(), "1 added, 1 changed, 1 removed");
+        assert_eq!(model.folder_paths().unwrap(), vec!["Root", "Root/Team"]);
+        assert_eq!(
+            names(&model),
+            vec!["Backup", "Deploy", "Prod DB", "Staging"]
+        );
+        assert_eq!(attachment_ids(&model, "prod"), vec!["prod-1"]);
+        assert!(attachment_ids(&model, "legacy").is_empty());
+        assert!(model.credentials_by_id("legacy").unwrap().is_none());
+        assert_eq!(
+            model
+                .credentials_by_id("deploy")
+                .unwrap()
+                .unwrap()
+                .folder_path,
+            "Root/Team"
+        );
+
+        let report = model.sync_root_folder(second_tree()).unwrap();
+        assert_eq!(report.to_string(), "0 added, 0 changed, 0 removed");
+    }
+
+    #[test]
+    fn keeps_the_database_if_a_sync_fails() {
+        let model = model();
+        model.sync_root_folder(first_tree()).unwrap();
+        model
+            .connection
+            .execute_batch(
+                "CREATE TRIGGER fail BEFORE INSERT ON credentials WHEN NEW.name = 'Backup'
+                 BEGIN SELECT RAISE(ABORT, 'broken entry'); END;",
+            )
+            .unwrap();
+
+        assert!(model.sync_root_folder(second_tree()).is_err());
+        assert_eq!(
+            model.folder_paths().unwrap(),
+            vec!["Root", "Root/Old", "Root/Team"]
+        );
+        assert_eq!(
+            names(&model),
+            vec!["Deploy", "Legacy", "Prod DB", "Staging"]
+        );
+        assert_eq!(attachment_ids(&model, "prod"), vec!["prod-1", "prod-2"]);
+        assert_eq!(attachment_ids(&model, "legacy"), vec!["legacy-1"]);
+
+        // the next sync does not see the changes of the failed one as done
+        model
+            .connection
+            .execute_batch("DROP TRIGGER fail;")
+            .unwrap();
+        let report = model.sync_root_folder(second_tree()).unwrap();
+        assert_eq!(report.to_string(), "1 added, 1 changed, 1 removed");
+    }
+}
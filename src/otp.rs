@@ -0,0 +1,109 @@
+//! One time passwords for accounts with two-factor authentication
+
+use crate::settings::SecureString;
+use crate::types::Result;
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use std::fs::OpenOptions;
+use std::io::{BufRead, BufReader, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Where the one time password comes from if the server asks for a second factor
+pub enum OtpSource {
+    /// a code given on the command line
+    Code(String),
+    /// a base32 encoded TOTP secret, as shown next to the QR code during the 2FA setup
+    TotpSecret(SecureString),
+    /// asks on the terminal
+    Prompt,
+}
+
+impl OtpSource {
+    pub fn one_time_password(&self, provider: &str) -> Result<String> {
+        match self {
+            OtpSource::Code(code) => Ok(code.clone()),
+            OtpSource::TotpSecret(secret) => {
+                let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+                totp(secret.as_str(), now)
+            }
+            OtpSource::Prompt => prompt(provider),
+        }
+    }
+}
+
+/// A six digit RFC 6238 code with a 30 seconds time step
+fn totp(secret: &str, unix_time: u64) -> Result<String> {
+    let secret: String = secret
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != '=')
+        .collect();
+    let key = base32::decode(
+        base32::Alphabet::RFC4648 { padding: false },
+        secret.to_uppercase().as_str(),
+    )
+    .ok_or("The TOTP secret is not base32 encoded")?;
+
+    let mut mac = Hmac::<Sha1>::new_from_slice(key.as_slice())?;
+    mac.update(&(unix_time / 30).to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let code = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+    Ok(format!("{:06}", code % 1_000_000))
+}
+
+/// Reads the code from the terminal, so it also works if stdin is redirected
+fn prompt(provider: &str) -> Result<String> {
+    let mut tty = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/tty")
+        .map_err(|err| {
+            format!(
+                "The server requires a one time password ({}) but there is no terminal to ask for it: {}. Use --otp or PLEASANT_PASSWORD_SERVER_TOTP_SECRET",
+                provider, err
+            )
+        })?;
+    write!(tty, "One time password ({}): ", provider)?;
+    tty.flush()?;
+
+    let mut code = String::new();
+    BufReader::new(tty).read_line(&mut code)?;
+    Ok(code.trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The ASCII secret `12345678901234567890` of RFC 6238 Appendix B, base32 encoded
+    const RFC_SECRET: &str = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+
+    #[test]
+    fn generates_the_codes_of_the_rfc() {
+        // the SHA-1 test vectors, which have 8 digits, end with these 6
+        let vectors = [
+            (59, "287082"),             // 94287082
+            (1_111_111_109, "081804"),  // 07081804
+            (1_111_111_111, "050471"),  // 14050471
+            (1_234_567_890, "005924"),  // 89005924
+            (2_000_000_000, "279037"),  // 69279037
+            (20_000_000_000, "353130"), // 65353130
+        ];
+        for (unix_time, code) in vectors.iter() {
+            assert_eq!(totp(RFC_SECRET, *unix_time).unwrap(), *code);
+        }
+    }
+
+    #[test]
+    fn accepts_secrets_as_shown_by_apps() {
+        let secret = "gezd gnbv gy3t qojq gezd gnbv gy3t qojq====";
+        assert_eq!(totp(secret, 59).unwrap(), "287082");
+        assert!(totp("not base32!", 59).is_err());
+    }
+}
@@ -0,0 +1,192 @@
+//! Rendering of command results in the format chosen with `--output`
+
+use crate::db::db_types::Folder;
+use crate::types::Result;
+use serde::Serialize;
+use serde_json::Value;
+use std::io::Write;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    Json,
+    /// one json object per line
+    Ndjson,
+    Csv,
+    Tsv,
+    /// aligned columns for humans
+    Table,
+    Yaml,
+}
+
+impl OutputFormat {
+    pub const VARIANTS: &'static [&'static str] =
+        &["json", "ndjson", "csv", "tsv", "table", "yaml"];
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "json" => Ok(OutputFormat::Json),
+            "ndjson" => Ok(OutputFormat::Ndjson),
+            "csv" => Ok(OutputFormat::Csv),
+            "tsv" => Ok(OutputFormat::Tsv),
+            "table" => Ok(OutputFormat::Table),
+            "yaml" => Ok(OutputFormat::Yaml),
+            _ => Err(format!(
+                "Unknown output format {}. Expected one of {}",
+                s,
+                OutputFormat::VARIANTS.join(", ")
+            )),
+        }
+    }
+}
+
+/// A folder or entry of the tree as a flat row
+#[derive(Debug, Serialize)]
+pub struct TreeRow {
+    kind: &'static str,
+    /// full path of the folder, for entries the path of the containing folder
+    path: String,
+    id: String,
+    name: String,
+    username: Option<String>,
+}
+
+/// Writes a list of records. For csv, tsv and table the records have to be flat structs.
+pub fn write_records<T: Serialize, W: Write>(
+    format: OutputFormat,
+    records: &[T],
+    mut writer: W,
+) -> Result<()> {
+    match format {
+        OutputFormat::Json => {
+            serde_json::to_writer_pretty(&mut writer, records)?;
+            writeln!(writer)?;
+        }
+        OutputFormat::Ndjson => {
+            for record in records.iter() {
+                serde_json::to_writer(&mut writer, record)?;
+                writeln!(writer)?;
+            }
+        }
+        OutputFormat::Csv => write_delimited(b',', records, writer)?,
+        OutputFormat::Tsv => write_delimited(b'\t', records, writer)?,
+        OutputFormat::Table => write_table(records, writer)?,
+        OutputFormat::Yaml => serde_yaml::to_writer(writer, records)?,
+    }
+    Ok(())
+}
+
+/// Writes a folder tree. Json and yaml keep the nesting, all other formats get one row per
+/// folder and entry.
+pub fn write_tree<W: Write>(
+    format: OutputFormat,
+    root_folder: &Folder,
+    mut writer: W,
+) -> Result<()> {
+    match format {
+        OutputFormat::Json => {
+            serde_json::to_writer_pretty(&mut writer, root_folder)?;
+            writeln!(writer)?;
+        }
+        OutputFormat::Yaml => serde_yaml::to_writer(writer, root_folder)?,
+        _ => {
+            let mut rows = Vec::new();
+            tree_rows(root_folder, "", &mut rows);
+            write_records(format, rows.as_slice(), writer)?;
+        }
+    }
+    Ok(())
+}
+
+fn tree_rows(folder: &Folder, parent_path: &str, rows: &mut Vec<TreeRow>) {
+    let path = if parent_path.is_empty() {
+        folder.name.clone()
+    } else {
+        format!("{}/{}", parent_path, folder.name)
+    };
+    rows.push(TreeRow {
+        kind: "folder",
+        path: path.clone(),
+        id: folder.id.clone(),
+        name: folder.name.clone(),
+        username: None,
+    });
+    for entry in folder.credentials.iter() {
+        rows.push(TreeRow {
+            kind: "entry",
+            path: path.clone(),
+            id: entry.id.clone(),
+            name: entry.name.clone(),
+            username: entry.username.clone(),
+        });
+    }
+    for child in folder.children.iter() {
+        tree_rows(child, path.as_str(), rows);
+    }
+}
+
+fn write_delimited<T: Serialize, W: Write>(delimiter: u8, records: &[T], writer: W) -> Result<()> {
+    let mut writer = csv::WriterBuilder::new()
+        .delimiter(delimiter)
+        .from_writer(writer);
+    for record in records.iter() {
+        writer.serialize(record)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+fn write_table<T: Serialize, W: Write>(records: &[T], mut writer: W) -> Result<()> {
+    let mut header: Vec<String> = Vec::new();
+    let mut rows: Vec<Vec<String>> = Vec::new();
+    for record in records.iter() {
+        let object = match serde_json::to_value(record)? {
+            Value::Object(object) => object,
+            _ => return Err("Only structs can be printed as table".into()),
+        };
+        if header.is_empty() {
+            header = object.keys().cloned().collect();
+        }
+        rows.push(object.values().map(table_cell).collect());
+    }
+
+    let mut widths: Vec<usize> = header.iter().map(|h| h.chars().count()).collect();
+    for row in rows.iter() {
+        for (width, cell) in widths.iter_mut().zip(row.iter()) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    if !header.is_empty() {
+        write_table_row(&mut writer, header.as_slice(), widths.as_slice())?;
+        let separator: Vec<String> = widths.iter().map(|width| "-".repeat(*width)).collect();
+        write_table_row(&mut writer, separator.as_slice(), widths.as_slice())?;
+    }
+    for row in rows.iter() {
+        write_table_row(&mut writer, row.as_slice(), widths.as_slice())?;
+    }
+    Ok(())
+}
+
+fn write_table_row<W: Write>(writer: &mut W, cells: &[String], widths: &[usize]) -> Result<()> {
+    let line = cells
+        .iter()
+        .zip(widths.iter())
+        .map(|(cell, width)| format!("{:width$}", cell, width = width))
+        .collect::<Vec<String>>()
+        .join("  ");
+    writeln!(writer, "{}", line.trim_end())?;
+    Ok(())
+}
+
+fn table_cell(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(s) => s.replace(&['\r', '\n'][..], " "),
+        other => other.to_string(),
+    }
+}
@@ -0,0 +1,56 @@
+//! Where the password of the Pleasant login comes from
+
+use crate::settings::SecureString;
+use crate::types::Result;
+use log::*;
+use std::process::{Command, Stdio};
+
+pub enum PasswordSource {
+    /// given in the environment or on stdin
+    Value(SecureString),
+    /// a shell command which prints the password, like a git credential helper
+    Command(String),
+    /// asks on the terminal without echo
+    Prompt,
+}
+
+impl PasswordSource {
+    /// Resolves the password. It is only called when a login is needed, so nothing is asked
+    /// for as long as there is a valid token in the cache.
+    pub fn password(&self, login: &str) -> Result<String> {
+        match self {
+            PasswordSource::Value(password) => Ok(password.as_str().to_string()),
+            PasswordSource::Command(command) => run_password_command(command.as_str()),
+            PasswordSource::Prompt => Ok(rpassword::read_password_from_tty(Some(
+                format!("Password for {}: ", login).as_str(),
+            ))
+            .map_err(|err| {
+                format!(
+                    "Could not ask for the password: {}. Set PLEASANT_PASSWORD_SERVER_PASSWORD, PLEASANT_PASSWORD_SERVER_PASSWORD_COMMAND or use --password-stdin",
+                    err
+                )
+            })?),
+        }
+    }
+}
+
+fn run_password_command(command: &str) -> Result<String> {
+    info!("Running password command");
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .output()?;
+    if !output.status.success() {
+        return Err(format!("The password command failed with {}", output.status).into());
+    }
+    let password = String::from_utf8(output.stdout)
+        .map_err(|_| "The password command printed no valid utf8")?;
+    // only the first line, like `pass` which may print further lines with metadata
+    let password = password.lines().next().unwrap_or_default().to_string();
+    if password.is_empty() {
+        return Err("The password command printed no password".into());
+    }
+    Ok(password)
+}
@@ -0,0 +1,741 @@
+//! The terminal UI of the `pick` subcommand, a fuzzy finder over the synced entries.
+//!
+//! The screen shows the folder tree, the entries of the selected folder and its subfolders which
+//! match the filter, and the details of the selected entry. It is drawn on stderr, so stdout only
+//! receives what was picked, e.g. for `$(pleasent-keepass-client-rs pick)`.
+
+use crate::db::db_types::Attachment;
+use crate::model::Credentials;
+use crate::types::Result;
+use crate::PleasantPasswordServerClient;
+use fuzzy_matcher::skim::SkimMatcherV2;
+use fuzzy_matcher::FuzzyMatcher;
+use ratatui::backend::CrosstermBackend;
+use ratatui::crossterm::cursor::Show;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use ratatui::crossterm::execute;
+use ratatui::crossterm::terminal::{
+    disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen,
+};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Modifier, Style};
+use ratatui::text::{Line, Span, Text};
+use ratatui::widgets::{Block, List, ListItem, ListState, Paragraph, Wrap};
+use ratatui::{Frame, Terminal};
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::fs::OpenOptions;
+use std::io::{Stderr, Write};
+
+/// Rows moved by page up and down
+const PAGE: usize = 10;
+
+/// What the user picked
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Field {
+    Password,
+    Username,
+    Id,
+}
+
+impl Field {
+    pub fn name(self) -> &'static str {
+        match self {
+            Field::Password => "password",
+            Field::Username => "username",
+            Field::Id => "id",
+        }
+    }
+}
+
+/// What happens with the picked field
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    Print,
+    Copy,
+}
+
+impl Action {
+    pub fn name(self) -> &'static str {
+        match self {
+            Action::Print => "print",
+            Action::Copy => "copy",
+        }
+    }
+
+    fn other(self) -> Action {
+        match self {
+            Action::Print => Action::Copy,
+            Action::Copy => Action::Print,
+        }
+    }
+}
+
+/// Lets the user pick a field of an entry and what to do with it, `None` if the picker was
+/// closed without a choice.
+///
+/// `query` is the initial filter. Enter, Ctrl-U and Ctrl-E pick with the `action`, the same keys
+/// with Alt with the other one.
+pub fn pick(
+    client: &PleasantPasswordServerClient,
+    query: &str,
+    action: Action,
+) -> Result<Option<(Credentials, Field, Action)>> {
+    let entries = client.query("", None)?;
+    if entries.is_empty() {
+        return Err(crate::Error::NotFound(
+            "There are no entries, maybe the local database needs a sync".to_string(),
+        ));
+    }
+    let mut picker = Picker::new(client, entries, client.folder_paths()?, query, action);
+
+    let mut terminal = Screen::enter()?;
+    loop {
+        terminal.0.draw(|frame| picker.draw(frame))?;
+        if let Event::Key(key) = event::read()? {
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+            match picker.handle_key(key) {
+                Outcome::Continue => {}
+                Outcome::Cancel => return Ok(None),
+                Outcome::Pick(field, action) => {
+                    return Ok(picker
+                        .selected_entry()
+                        .map(|index| (picker.entries.swap_remove(index), field, action)))
+                }
+            }
+        }
+    }
+}
+
+/// Copies the value to the clipboard of the terminal with OSC 52, which also works over SSH.
+/// Inside tmux it needs `set -g set-clipboard on`. The sequence goes to the controlling terminal,
+/// never to a redirected stderr, where it would leave the value in a log.
+pub fn copy_to_clipboard(value: &str) -> Result<()> {
+    let mut terminal = OpenOptions::new()
+        .write(true)
+        .open("/dev/tty")
+        .map_err(|err| format!("There is no terminal to copy to: {}", err))?;
+    write!(terminal, "\x1b]52;c;{}\x07", base64::encode(value))?;
+    terminal.flush()?;
+    Ok(())
+}
+
+#[derive(Debug, PartialEq)]
+enum Outcome {
+    Continue,
+    Cancel,
+    Pick(Field, Action),
+}
+
+#[derive(PartialEq)]
+enum Focus {
+    Folders,
+    Entries,
+}
+
+struct Picker<'c> {
+    client: &'c PleasantPasswordServerClient,
+    entries: Vec<Credentials>,
+    /// full paths of all folders, sorted so children follow their parent
+    folders: Vec<String>,
+    expanded: HashSet<String>,
+    /// the folder whose entries are shown, with all subfolders
+    folder: String,
+    query: String,
+    /// indexes of the entries in the folder matching the query, best match first
+    matches: Vec<usize>,
+    entry_list: ListState,
+    focus: Focus,
+    /// attachments of the entries shown so far, read from the database once
+    attachments: HashMap<String, Vec<Attachment>>,
+    matcher: SkimMatcherV2,
+    /// the action of the keys without Alt
+    action: Action,
+}
+
+impl<'c> Picker<'c> {
+    fn new(
+        client: &'c PleasantPasswordServerClient,
+        entries: Vec<Credentials>,
+        mut folders: Vec<String>,
+        query: &str,
+        action: Action,
+    ) -> Picker<'c> {
+        // entries of folders the database does not know, e.g. the root of an older sync
+        for entry in entries.iter() {
+            if !folders.contains(&entry.folder_path) {
+                folders.push(entry.folder_path.clone());
+            }
+        }
+        sort_folders(&mut folders);
+        let roots: Vec<String> = folders
+            .iter()
+            .filter(|folder| !folder.contains('/'))
+            .cloned()
+            .collect();
+        let folder = if roots.len() == 1 {
+            roots[0].clone()
+        } else {
+            String::new()
+        };
+        let mut picker = Picker {
+            client,
+            entries,
+            folders,
+            expanded: roots.into_iter().collect(),
+            folder,
+            query: query.to_string(),
+            matches: Vec::new(),
+            entry_list: ListState::default(),
+            focus: Focus::Entries,
+            attachments: HashMap::new(),
+            matcher: SkimMatcherV2::default(),
+            action,
+        };
+        picker.filter();
+        picker
+    }
+
+    /// Matches the entries of the folder against the query and selects the best match
+    fn filter(&mut self) {
+        let mut scored: Vec<(i64, usize)> = self
+            .entries
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| in_folder(entry.folder_path.as_str(), self.folder.as_str()))
+            .filter_map(|(index, entry)| {
+                if self.query.is_empty() {
+                    return Some((0, index));
+                }
+                let haystack = format!(
+                    "{}/{} {}",
+                    entry.folder_path,
+                    entry.name,
+                    entry.username.as_deref().unwrap_or("")
+                );
+                self.matcher
+                    .fuzzy_match(haystack.as_str(), self.query.as_str())
+                    .map(|score| (score, index))
+            })
+            .collect();
+        // stable, so entries with the same score stay sorted by name
+        scored.sort_by_key(|(score, _)| Reverse(*score));
+        self.matches = scored.into_iter().map(|(_, index)| index).collect();
+        self.entry_list.select(if self.matches.is_empty() {
+            None
+        } else {
+            Some(0)
+        });
+    }
+
+    fn selected_entry(&self) -> Option<usize> {
+        self.entry_list
+            .selected()
+            .and_then(|position| self.matches.get(position).copied())
+    }
+
+    fn visible_folders(&self) -> Vec<&String> {
+        visible_folders(self.folders.as_slice(), &self.expanded)
+    }
+
+    fn has_subfolders(&self, folder: &str) -> bool {
+        self.folders
+            .iter()
+            .any(|other| other.len() > folder.len() && in_folder(other, folder))
+    }
+
+    fn handle_key(&mut self, key: KeyEvent) -> Outcome {
+        let control = key.modifiers.contains(KeyModifiers::CONTROL);
+        let alt = key.modifiers.contains(KeyModifiers::ALT);
+        let action = if alt {
+            self.action.other()
+        } else {
+            self.action
+        };
+        match key.code {
+            KeyCode::Char('c') if control => return Outcome::Cancel,
+            KeyCode::Char('u') if control || alt => return self.pick(Field::Username, action),
+            KeyCode::Char('e') if control || alt => return self.pick(Field::Id, action),
+            KeyCode::Enter => return self.pick(Field::Password, action),
+            KeyCode::Esc if self.query.is_empty() => return Outcome::Cancel,
+            KeyCode::Esc => {
+                self.query.clear();
+                self.filter();
+            }
+            KeyCode::Char(c) if !control && !alt => {
+                self.query.push(c);
+                self.filter();
+            }
+            KeyCode::Backspace => {
+                self.query.pop();
+                self.filter();
+            }
+            KeyCode::Tab | KeyCode::BackTab => {
+                self.focus = match self.focus {
+                    Focus::Folders => Focus::Entries,
+                    Focus::Entries => Focus::Folders,
+                }
+            }
+            KeyCode::Up => self.move_selection(-1),
+            KeyCode::Down => self.move_selection(1),
+            KeyCode::PageUp => self.move_selection(-(PAGE as isize)),
+            KeyCode::PageDown => self.move_selection(PAGE as isize),
+            KeyCode::Left if self.focus == Focus::Folders => self.collapse(),
+            KeyCode::Right
+                if self.focus == Focus::Folders && self.has_subfolders(self.folder.as_str()) =>
+            {
+                self.expanded.insert(self.folder.clone());
+            }
+            _ => {}
+        }
+        Outcome::Continue
+    }
+
+    fn pick(&self, field: Field, action: Action) -> Outcome {
+        match self.selected_entry() {
+            Some(_) => Outcome::Pick(field, action),
+            None => Outcome::Continue,
+        }
+    }
+
+    fn move_selection(&mut self, offset: isize) {
+        match self.focus {
+            Focus::Entries => {
+                if let Some(selected) = self.entry_list.selected() {
+                    self.entry_list
+                        .select(Some(step(selected, offset, self.matches.len())));
+                }
+            }
+            Focus::Folders => {
+                let visible = self.visible_folders();
+                let selected = visible
+                    .iter()
+                    .position(|folder| **folder == self.folder)
+                    .unwrap_or(0);
+                if let Some(folder) = visible.get(step(selected, offset, visible.len())) {
+                    self.folder = folder.to_string();
+                    self.filter();
+                }
+            }
+        }
+    }
+
+    /// Collapses the selected folder, or selects its parent if it is collapsed already
+    fn collapse(&mut self) {
+        if self.expanded.remove(self.folder.as_str()) {
+            return;
+        }
+        if let Some(index) = self.folder.rfind('/') {
+            self.folder.truncate(index);
+            self.filter();
+        }
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [filter_area, main_area, help_area] = Layout::vertical([
+            Constraint::Length(3),
+            Constraint::Min(0),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+        let [folder_area, entry_area, details_area] = Layout::horizontal([
+            Constraint::Percentage(25),
+            Constraint::Percentage(40),
+            Constraint::Percentage(35),
+        ])
+        .areas(main_area);
+
+        let filter = Paragraph::new(self.query.as_str()).block(Block::bordered().title(format!(
+            "Filter ({}/{})",
+            self.matches.len(),
+            self.entries.len()
+        )));
+        frame.render_widget(filter, filter_area);
+        frame.set_cursor_position((
+            filter_area.x + 1 + self.query.chars().count() as u16,
+            filter_area.y + 1,
+        ));
+
+        self.draw_folders(frame, folder_area);
+        self.draw_entries(frame, entry_area);
+        self.draw_details(frame, details_area);
+
+        frame.render_widget(
+            Paragraph::new(self.help()).style(Style::new().add_modifier(Modifier::DIM)),
+            help_area,
+        );
+    }
+
+    fn help(&self) -> String {
+        format!(
+            "Enter {action} password  Ctrl-U {action} username  Ctrl-E {action} id  Alt+key {other}  Tab folders/entries  Esc quit",
+            action = self.action.name(),
+            other = self.action.other().name()
+        )
+    }
+
+    fn draw_folders(&self, frame: &mut Frame, area: Rect) {
+        let visible = self.visible_folders();
+        let items: Vec<ListItem> = visible
+            .iter()
+            .map(|folder| {
+                let depth = folder.matches('/').count();
+                let marker = if !self.has_subfolders(folder) {
+                    "  "
+                } else if self.expanded.contains(folder.as_str()) {
+                    "▾ "
+                } else {
+                    "▸ "
+                };
+                let name = folder.rsplit('/').next().unwrap_or(folder);
+                ListItem::new(format!("{}{}{}", "  ".repeat(depth), marker, name))
+            })
+            .collect();
+        let mut state =
+            ListState::default().with_selected(visible.iter().position(|f| **f == self.folder));
+        let list = List::new(items)
+            .block(self.block("Folders", self.focus == Focus::Folders))
+            .highlight_style(Style::new().add_modifier(Modifier::REVERSED));
+        frame.render_stateful_widget(list, area, &mut state);
+    }
+
+    fn draw_entries(&mut self, frame: &mut Frame, area: Rect) {
+        let items: Vec<ListItem> = self
+            .matches
+            .iter()
+            .map(|index| {
+                let entry = &self.entries[*index];
+                let mut spans = vec![Span::raw(entry.name.clone())];
+                if let Some(username) = &entry.username {
+                    spans.push(Span::styled(
+                        format!("  {}", username),
+                        Style::new().add_modifier(Modifier::DIM),
+                    ));
+                }
+                ListItem::new(Line::from(spans))
+            })
+            .collect();
+        let list = List::new(items)
+            .block(self.block("Entries", self.focus == Focus::Entries))
+            .highlight_style(Style::new().add_modifier(Modifier::REVERSED));
+        frame.render_stateful_widget(list, area, &mut self.entry_list);
+    }
+
+    fn draw_details(&mut self, frame: &mut Frame, area: Rect) {
+        let mut lines: Vec<Line> = Vec::new();
+        if let Some(index) = self.selected_entry() {
+            let id = self.entries[index].id.clone();
+            if !self.attachments.contains_key(id.as_str()) {
+                // only the display suffers if the database can not be read
+                let attachments = self.client.attachments(id.as_str()).unwrap_or_default();
+                self.attachments.insert(id.clone(), attachments);
+            }
+            let entry = &self.entries[index];
+            let bold = Style::new().add_modifier(Modifier::BOLD);
+            lines.push(Line::styled(entry.name.clone(), bold));
+            lines.push(Line::from(entry.folder_path.clone()));
+            lines.push(Line::from(""));
+            lines.push(Line::from(vec![
+                Span::styled("Username  ", bold),
+                Span::raw(entry.username.clone().unwrap_or_default()),
+            ]));
+            lines.push(Line::from(vec![
+                Span::styled("Id        ", bold),
+                Span::raw(entry.id.clone()),
+            ]));
+            let notes = entry.notes.as_deref().unwrap_or("").trim();
+            if !notes.is_empty() {
+                lines.push(Line::from(""));
+                lines.push(Line::styled("Notes", bold));
+                lines.extend(notes.lines().map(|line| Line::from(line.to_string())));
+            }
+            let attachments = &self.attachments[id.as_str()];
+            if !attachments.is_empty() {
+                lines.push(Line::from(""));
+                lines.push(Line::styled("Attachments", bold));
+                lines.extend(attachments.iter().map(|attachment| {
+                    Line::from(format!(
+                        "{} ({} bytes)",
+                        attachment.file_name, attachment.file_size
+                    ))
+                }));
+            }
+        }
+        let details = Paragraph::new(Text::from(lines))
+            .block(Block::bordered().title("Details"))
+            .wrap(Wrap { trim: false });
+        frame.render_widget(details, area);
+    }
+
+    fn block(&self, title: &'static str, focused: bool) -> Block<'static> {
+        let block = Block::bordered().title(title);
+        if focused {
+            block.border_style(Style::new().add_modifier(Modifier::BOLD))
+        } else {
+            block
+        }
+    }
+}
+
+/// Sorts by segment, so children follow their parent. A plain sort would put `Root/A B` between
+/// `Root/A` and `Root/A/C`.
+fn sort_folders(folders: &mut [String]) {
+    folders.sort_by(|a, b| a.split('/').cmp(b.split('/')));
+}
+
+/// The folders whose parents are all expanded, in tree order
+fn visible_folders<'f>(folders: &'f [String], expanded: &HashSet<String>) -> Vec<&'f String> {
+    folders
+        .iter()
+        .filter(|folder| {
+            let mut parent = folder.as_str();
+            while let Some(index) = parent.rfind('/') {
+                parent = &parent[..index];
+                if !expanded.contains(parent) {
+                    return false;
+                }
+            }
+            true
+        })
+        .collect()
+}
+
+/// The folder itself or one of its subfolders, every path is below the empty folder
+fn in_folder(path: &str, folder: &str) -> bool {
+    folder.is_empty()
+        || path == folder
+        || (path.starts_with(folder) && path[folder.len()..].starts_with('/'))
+}
+
+/// Moves an index by the offset, staying within the length
+fn step(index: usize, offset: isize, length: usize) -> usize {
+    if length == 0 {
+        return 0;
+    }
+    (index as isize + offset).clamp(0, length as isize - 1) as usize
+}
+
+/// The terminal in raw mode on the alternate screen, restored when dropped
+struct Screen(Terminal<CrosstermBackend<Stderr>>, Restore);
+
+impl Screen {
+    fn enter() -> Result<Screen> {
+        enable_raw_mode()?;
+        // from here on every error restores the terminal
+        let restore = Restore;
+        execute!(std::io::stderr(), EnterAlternateScreen)?;
+        let terminal = Terminal::new(CrosstermBackend::new(std::io::stderr()))?;
+        Ok(Screen(terminal, restore))
+    }
+}
+
+/// Leaves raw mode and the alternate screen, also if only some of it was entered
+struct Restore;
+
+impl Drop for Restore {
+    fn drop(&mut self) {
+        let _ = disable_raw_mode();
+        let _ = execute!(std::io::stderr(), LeaveAlternateScreen, Show);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache_policy::CachePolicy;
+    use crate::{Account, CacheKey, OtpSource, PasswordSource};
+    use std::path::PathBuf;
+
+    fn folders(paths: &[&str]) -> Vec<String> {
+        paths.iter().map(|path| path.to_string()).collect()
+    }
+
+    /// A client which reaches no server, the picker only reads attachments with it
+    struct TestClient(PleasantPasswordServerClient, PathBuf);
+
+    impl TestClient {
+        fn new(name: &str) -> TestClient {
+            let dir = std::env::temp_dir().join(format!(
+                "pleasant-picker-{}-{}",
+                name,
+                std::process::id()
+            ));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            let account = Account {
+                login: "jdoe".to_string(),
+                password: PasswordSource::Value("s3cret".to_string().into()),
+                otp: OtpSource::Prompt,
+            };
+            let client = PleasantPasswordServerClient::new(
+                url::Url::parse("http://127.0.0.1:1/").unwrap(),
+                reqwest::Client::new(),
+                account,
+                dir.clone(),
+                CacheKey::KeyFile(dir.join("cache.key")),
+                CachePolicy::default(),
+            )
+            .unwrap();
+            TestClient(client, dir)
+        }
+
+        fn picker(&self, action: Action) -> Picker<'_> {
+            let entry = |id: &str, name: &str| Credentials {
+                id: id.to_string(),
+                folder_name: "Prod".to_string(),
+                folder_path: "Root/Prod".to_string(),
+                name: name.to_string(),
+                username: Some("admin".to_string()),
+                notes: None,
+            };
+            let entries = vec![entry("1", "Prod DB"), entry("2", "Prod Web")];
+            Picker::new(
+                &self.0,
+                entries,
+                folders(&["Root", "Root/Prod"]),
+                "",
+                action,
+            )
+        }
+    }
+
+    impl Drop for TestClient {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.1);
+        }
+    }
+
+    fn key(code: KeyCode, modifiers: KeyModifiers) -> KeyEvent {
+        KeyEvent::new(code, modifiers)
+    }
+
+    #[test]
+    fn copies_and_prints_with_separate_keys() {
+        let client = TestClient::new("keys");
+        let mut picker = client.picker(Action::Print);
+        let (none, control, alt) = (KeyModifiers::NONE, KeyModifiers::CONTROL, KeyModifiers::ALT);
+        let picks = [
+            (KeyCode::Enter, none, Field::Password, Action::Print),
+            (KeyCode::Enter, alt, Field::Password, Action::Copy),
+            (KeyCode::Char('u'), control, Field::Username, Action::Print),
+            (KeyCode::Char('u'), alt, Field::Username, Action::Copy),
+            (KeyCode::Char('e'), control, Field::Id, Action::Print),
+            (KeyCode::Char('e'), alt, Field::Id, Action::Copy),
+        ];
+        for (code, modifiers, field, action) in picks.iter() {
+            assert_eq!(
+                picker.handle_key(key(*code, *modifiers)),
+                Outcome::Pick(*field, *action)
+            );
+        }
+
+        // --copy swaps the actions
+        let mut picker = client.picker(Action::Copy);
+        assert_eq!(
+            picker.handle_key(key(KeyCode::Enter, none)),
+            Outcome::Pick(Field::Password, Action::Copy)
+        );
+        assert_eq!(
+            picker.handle_key(key(KeyCode::Char('u'), alt)),
+            Outcome::Pick(Field::Username, Action::Print)
+        );
+    }
+
+    #[test]
+    fn types_letters_and_picks_only_a_match() {
+        let client = TestClient::new("typing");
+        let mut picker = client.picker(Action::Print);
+        for c in "web".chars() {
+            assert_eq!(
+                picker.handle_key(key(KeyCode::Char(c), KeyModifiers::NONE)),
+                Outcome::Continue
+            );
+        }
+        assert_eq!(picker.query, "web");
+        assert_eq!(picker.matches.len(), 1);
+
+        picker.handle_key(key(KeyCode::Char('x'), KeyModifiers::SHIFT));
+        assert!(picker.matches.is_empty());
+        assert_eq!(
+            picker.handle_key(key(KeyCode::Enter, KeyModifiers::NONE)),
+            Outcome::Continue
+        );
+        assert_eq!(
+            picker.handle_key(key(KeyCode::Char('e'), KeyModifiers::ALT)),
+            Outcome::Continue
+        );
+        assert_eq!(picker.query, "webx");
+
+        picker.handle_key(key(KeyCode::Esc, KeyModifiers::NONE));
+        assert_eq!(picker.query, "");
+        assert_eq!(
+            picker.handle_key(key(KeyCode::Esc, KeyModifiers::NONE)),
+            Outcome::Cancel
+        );
+    }
+
+    #[test]
+    fn lists_both_actions_in_the_help() {
+        let client = TestClient::new("help");
+        assert_eq!(
+            client.picker(Action::Print).help(),
+            "Enter print password  Ctrl-U print username  Ctrl-E print id  Alt+key copy  Tab folders/entries  Esc quit"
+        );
+        assert!(client.picker(Action::Copy).help().starts_with(
+            "Enter copy password  Ctrl-U copy username  Ctrl-E copy id  Alt+key print"
+        ));
+    }
+
+    #[test]
+    fn matches_folders_and_subfolders() {
+        assert!(in_folder("Root/Prod", "Root/Prod"));
+        assert!(in_folder("Root/Prod/DB", "Root/Prod"));
+        assert!(!in_folder("Root/Production", "Root/Prod"));
+        assert!(!in_folder("Root", "Root/Prod"));
+        assert!(in_folder("Root/Prod", ""));
+    }
+
+    #[test]
+    fn steps_within_the_list() {
+        assert_eq!(step(3, 1, 5), 4);
+        assert_eq!(step(4, 1, 5), 4);
+        assert_eq!(step(1, -(PAGE as isize), 5), 0);
+        assert_eq!(step(0, PAGE as isize, 5), 4);
+        assert_eq!(step(0, 1, 0), 0);
+    }
+
+    #[test]
+    fn sorts_children_after_their_parent() {
+        let mut paths = folders(&["Root/A B", "Root/A/C", "Root", "Root/A", "Root/A-B"]);
+        sort_folders(&mut paths);
+        assert_eq!(
+            paths,
+            folders(&["Root", "Root/A", "Root/A/C", "Root/A B", "Root/A-B"])
+        );
+    }
+
+    #[test]
+    fn shows_folders_whose_parents_are_expanded() {
+        let paths = folders(&["Root", "Root/A", "Root/A/C", "Root/A/C/D", "Root/B"]);
+        let visible = |expanded: &[&str]| {
+            let expanded = expanded.iter().map(|path| path.to_string()).collect();
+            visible_folders(paths.as_slice(), &expanded)
+                .into_iter()
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(visible(&[]), folders(&["Root"]));
+        assert_eq!(visible(&["Root"]), folders(&["Root", "Root/A", "Root/B"]));
+        assert_eq!(
+            visible(&["Root", "Root/A"]),
+            folders(&["Root", "Root/A", "Root/A/C", "Root/B"])
+        );
+        // a collapsed parent hides its subfolders even if they are expanded
+        assert_eq!(
+            visible(&["Root", "Root/A/C"]),
+            folders(&["Root", "Root/A", "Root/B"])
+        );
+    }
+}
@@ -0,0 +1,449 @@
+//! The query language of the `query` subcommand.
+//!
+//! A query is a whitespace separated list of free text terms and field filters, e.g.
+//! `folder:Prod/DB user:admin expires:<30d has:attachment name:"billing api" postgres`.
+//! Free text terms are looked up in the full text index, field filters are compiled into
+//! parameterized SQL against the `credentials`, `folders` and `attachments` tables.
+
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use rusqlite::ToSql;
+use std::error::Error;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+
+#[derive(Debug, PartialEq)]
+pub struct Query {
+    pub terms: Vec<String>,
+    pub filters: Vec<Filter>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Filter {
+    /// the full folder path contains the value
+    Folder(String),
+    /// the entry is in the folder with this full path or below
+    Path(String),
+    User(String),
+    Name(String),
+    /// the name is the value, ignoring case
+    Entry(String),
+    Notes(String),
+    Id(String),
+    Expires(Comparison, DateTime<Utc>),
+    Has(Property),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Comparison {
+    Before,
+    After,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Property {
+    Attachment,
+    Notes,
+    Username,
+    Expiry,
+}
+
+/// A query which could not be parsed. The position is the 1-based character offset.
+#[derive(Debug)]
+pub struct QueryError {
+    position: usize,
+    message: String,
+}
+
+impl Display for QueryError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(
+            f,
+            "Invalid query at position {}: {}",
+            self.position, self.message
+        )
+    }
+}
+
+impl Error for QueryError {}
+
+/// Filters of a query as SQL conditions with positional parameters
+pub struct SqlFilter {
+    pub conditions: Vec<String>,
+    pub params: Vec<Box<dyn ToSql>>,
+}
+
+struct Token {
+    position: usize,
+    field: Option<String>,
+    value: String,
+}
+
+const FIELDS: &str = "folder, path, user, name, entry, notes, id, expires, has";
+
+impl Query {
+    pub fn parse(input: &str) -> Result<Query, QueryError> {
+        let mut query = Query {
+            terms: Vec::new(),
+            filters: Vec::new(),
+        };
+
+        for token in tokenize(input)?.into_iter() {
+            let field = match token.field {
+                None => {
+                    query.terms.push(token.value);
+                    continue;
+                }
+                Some(field) => field,
+            };
+            let position = token.position;
+            let error = |message: String| QueryError { position, message };
+            let value = token.value;
+            let filter = match field.to_lowercase().as_str() {
+                "folder" => Filter::Folder(value),
+                "path" => Filter::Path(value.trim_end_matches('/').to_string()),
+                "user" => Filter::User(value),
+                "name" => Filter::Name(value),
+                "entry" => Filter::Entry(value),
+                "notes" => Filter::Notes(value),
+                "id" => Filter::Id(value),
+                "expires" => {
+                    let (comparison, date) = parse_expires(value.as_str()).map_err(error)?;
+                    Filter::Expires(comparison, date)
+                }
+                "has" => Filter::Has(parse_property(value.as_str()).map_err(error)?),
+                _ => {
+                    return Err(error(format!(
+                        "unknown field '{}', expected one of {}",
+                        field, FIELDS
+                    )))
+                }
+            };
+            query.filters.push(filter);
+        }
+
+        Ok(query)
+    }
+
+    /// The free text terms as fts5 match expression of quoted prefix queries.
+    ///
+    /// Returns an empty string if the query has no free text terms.
+    pub fn match_expression(&self) -> String {
+        self.terms
+            .iter()
+            .map(|term| format!("\"{}\"*", term.replace('"', "\"\"")))
+            .collect::<Vec<String>>()
+            .join(" ")
+    }
+
+    /// Compiles the field filters. `c` is the credentials table and `p` the folder_paths view.
+    pub fn sql_filter(&self) -> SqlFilter {
+        let mut conditions: Vec<String> = Vec::new();
+        let mut params: Vec<Box<dyn ToSql>> = Vec::new();
+        for filter in self.filters.iter() {
+            let (condition, filter_params): (&str, Vec<Box<dyn ToSql>>) = match filter {
+                Filter::Folder(value) => ("p.path LIKE ? ESCAPE '\\'", vec![like(value)]),
+                Filter::Path(value) => (
+                    "p.path LIKE ? ESCAPE '\\' OR p.path LIKE ? ESCAPE '\\'",
+                    vec![
+                        Box::new(escape_like(value)),
+                        Box::new(format!("{}/%", escape_like(value))),
+                    ],
+                ),
+                Filter::User(value) => ("c.username LIKE ? ESCAPE '\\'", vec![like(value)]),
+                Filter::Name(value) => ("c.name LIKE ? ESCAPE '\\'", vec![like(value)]),
+                Filter::Entry(value) => {
+                    ("c.name = ? COLLATE NOCASE", vec![Box::new(value.clone())])
+                }
+                Filter::Notes(value) => ("c.notes LIKE ? ESCAPE '\\'", vec![like(value)]),
+                Filter::Id(value) => ("c.id = ?", vec![Box::new(value.clone())]),
+                Filter::Expires(Comparison::Before, date) => (
+                    "c.expires IS NOT NULL AND c.expires < ?",
+                    vec![Box::new(*date)],
+                ),
+                Filter::Expires(Comparison::After, date) => (
+                    "c.expires IS NOT NULL AND c.expires > ?",
+                    vec![Box::new(*date)],
+                ),
+                Filter::Has(Property::Attachment) => (
+                    "EXISTS (SELECT 1 FROM attachments a WHERE a.credentials_id = c.id)",
+                    vec![],
+                ),
+                Filter::Has(Property::Notes) => ("c.notes IS NOT NULL AND c.notes != ''", vec![]),
+                Filter::Has(Property::Username) => {
+                    ("c.username IS NOT NULL AND c.username != ''", vec![])
+                }
+                Filter::Has(Property::Expiry) => ("c.expires IS NOT NULL", vec![]),
+            };
+            conditions.push(format!("({})", condition));
+            params.extend(filter_params);
+        }
+        SqlFilter { conditions, params }
+    }
+}
+
+/// Quotes a value, so the query reads it as one value whatever it contains, e.g. for
+/// `format!("path:{}", quote(folder))`
+pub fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>, QueryError> {
+    let chars: Vec<char> = input.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    loop {
+        while i < chars.len() && chars[i].is_whitespace() {
+            i += 1;
+        }
+        if i >= chars.len() {
+            break;
+        }
+
+        let start = i;
+        let mut end = i;
+        while end < chars.len() && chars[end].is_ascii_alphabetic() {
+            end += 1;
+        }
+        let field = if end > i && end < chars.len() && chars[end] == ':' {
+            let field: String = chars[i..end].iter().collect();
+            i = end + 1;
+            Some(field)
+        } else {
+            None
+        };
+
+        let mut value = String::new();
+        if i < chars.len() && chars[i] == '"' {
+            let quote = i;
+            i += 1;
+            loop {
+                match chars.get(i) {
+                    None => {
+                        return Err(QueryError {
+                            position: quote + 1,
+                            message: "unterminated quote".to_string(),
+                        })
+                    }
+                    Some('"') => break,
+                    Some('\\') if i + 1 < chars.len() => {
+                        value.push(chars[i + 1]);
+                        i += 1;
+                    }
+                    Some(c) => value.push(*c),
+                }
+                i += 1;
+            }
+            i += 1;
+        } else {
+            while i < chars.len() && !chars[i].is_whitespace() {
+                value.push(chars[i]);
+                i += 1;
+            }
+        }
+
+        if let Some(field) = &field {
+            if value.is_empty() {
+                return Err(QueryError {
+                    position: start + 1,
+                    message: format!("missing value for field '{}'", field),
+                });
+            }
+        }
+        tokens.push(Token {
+            position: start + 1,
+            field,
+            value,
+        });
+    }
+    Ok(tokens)
+}
+
+/// Parses `<30d`, `>1w` or `<2021-12-31`. Durations are relative to now.
+fn parse_expires(value: &str) -> Result<(Comparison, DateTime<Utc>), String> {
+    let comparison = match value.chars().next() {
+        Some('<') => Comparison::Before,
+        Some('>') => Comparison::After,
+        _ => {
+            return Err(format!(
+                "expected < or > in front of '{}', e.g. expires:<30d",
+                value
+            ))
+        }
+    };
+    let value = &value[1..];
+
+    if let Ok(date) = NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        return Ok((comparison, DateTime::from_utc(date.and_hms(0, 0, 0), Utc)));
+    }
+
+    let invalid = || {
+        format!(
+            "'{}' is neither a date (YYYY-MM-DD) nor a duration like 12h, 30d, 2w or 1y",
+            value
+        )
+    };
+    let (index, unit) = value.char_indices().last().ok_or_else(invalid)?;
+    let amount: i64 = value[..index].parse().map_err(|_| invalid())?;
+    let unit_seconds: i64 = match unit {
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        'w' => 7 * 24 * 60 * 60,
+        'y' => 365 * 24 * 60 * 60,
+        _ => return Err(invalid()),
+    };
+    let out_of_range = || format!("the duration '{}' is too long", value);
+    let max_seconds = Duration::max_value().num_seconds();
+    let seconds = amount
+        .checked_mul(unit_seconds)
+        .filter(|seconds| (-max_seconds..=max_seconds).contains(seconds))
+        .ok_or_else(out_of_range)?;
+    let date = Utc::now()
+        .checked_add_signed(Duration::seconds(seconds))
+        .ok_or_else(out_of_range)?;
+    Ok((comparison, date))
+}
+
+fn parse_property(value: &str) -> Result<Property, String> {
+    match value.to_lowercase().as_str() {
+        "attachment" | "attachments" => Ok(Property::Attachment),
+        "notes" => Ok(Property::Notes),
+        "user" | "username" => Ok(Property::Username),
+        "expiry" | "expires" => Ok(Property::Expiry),
+        _ => Err(format!(
+            "unknown property '{}', expected one of attachment, notes, username, expiry",
+            value
+        )),
+    }
+}
+
+/// A `LIKE` pattern which matches the value anywhere
+fn like(value: &str) -> Box<dyn ToSql> {
+    Box::new(format!("%{}%", escape_like(value)))
+}
+
+fn escape_like(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(input: &str) -> String {
+        Query::parse(input).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn splits_terms_and_filters() {
+        let query = Query::parse("  postgres user:admin  Name:prod ").unwrap();
+        assert_eq!(query.terms, vec!["postgres".to_string()]);
+        assert_eq!(
+            query.filters,
+            vec![
+                Filter::User("admin".to_string()),
+                Filter::Name("prod".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn matches_names_exactly_with_entry() {
+        let query = Query::parse(r#"entry:"gitlab.example.com""#).unwrap();
+        assert_eq!(
+            query.filters,
+            vec![Filter::Entry("gitlab.example.com".to_string())]
+        );
+        assert_eq!(
+            query.sql_filter().conditions,
+            vec!["(c.name = ? COLLATE NOCASE)"]
+        );
+    }
+
+    #[test]
+    fn reads_quoted_values_with_escapes() {
+        let query = Query::parse(r#"name:"billing api" "say \"hi\"" notes:"a\\b""#).unwrap();
+        assert_eq!(query.terms, vec![r#"say "hi""#.to_string()]);
+        assert_eq!(
+            query.filters,
+            vec![
+                Filter::Name("billing api".to_string()),
+                Filter::Notes(r"a\b".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_colons_which_are_no_field() {
+        let query = Query::parse(r#"10:30 "https://example.com""#).unwrap();
+        assert_eq!(
+            query.terms,
+            vec!["10:30".to_string(), "https://example.com".to_string()]
+        );
+        assert!(query.filters.is_empty());
+        assert!(error("https://example.com").contains("unknown field 'https'"));
+    }
+
+    #[test]
+    fn trims_the_slash_of_paths() {
+        let query = Query::parse("path:Root/Prod/").unwrap();
+        assert_eq!(query.filters, vec![Filter::Path("Root/Prod".to_string())]);
+    }
+
+    #[test]
+    fn reports_the_position_of_errors() {
+        assert_eq!(
+            error(r#"a name:"open"#),
+            "Invalid query at position 8: unterminated quote"
+        );
+        assert_eq!(
+            error("a user:"),
+            "Invalid query at position 3: missing value for field 'user'"
+        );
+        assert!(error("x colour:red").starts_with("Invalid query at position 3: unknown field"));
+        assert!(error("has:wings").contains("unknown property 'wings'"));
+    }
+
+    #[test]
+    fn parses_expiry_dates_and_durations() {
+        let query = Query::parse("expires:<2021-12-31 expires:>2w").unwrap();
+        match &query.filters[..] {
+            [Filter::Expires(Comparison::Before, date), Filter::Expires(Comparison::After, later)] =>
+            {
+                assert_eq!(date.to_rfc3339(), "2021-12-31T00:00:00+00:00");
+                let expected = Utc::now() + Duration::weeks(2);
+                assert!((expected - *later).num_seconds().abs() < 5);
+            }
+            filters => panic!("unexpected filters {:?}", filters),
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_expiry() {
+        assert!(error("expires:30d").contains("expected < or >"));
+        assert!(error("expires:<d").contains("neither a date"));
+        assert!(error("expires:<3ä").contains("neither a date"));
+        assert!(error("expires:<ä").contains("neither a date"));
+        assert!(error("expires:<99999999999y").contains("too long"));
+        assert!(error("expires:<9223372036854775807h").contains("too long"));
+    }
+
+    #[test]
+    fn quotes_values() {
+        let folder = r#"Root/Team "A"\B/"#;
+        let query = Query::parse(format!("path:{} x", quote(folder)).as_str()).unwrap();
+        assert_eq!(
+            query.filters,
+            vec![Filter::Path(r#"Root/Team "A"\B"#.to_string())]
+        );
+        assert_eq!(query.terms, vec!["x".to_string()]);
+    }
+
+    #[test]
+    fn quotes_terms_for_fts5() {
+        let query = Query::parse(r#"prod "a\"b""#).unwrap();
+        assert_eq!(query.match_expression(), r#""prod"* "a""b"*"#);
+        assert_eq!(Query::parse("user:x").unwrap().match_expression(), "");
+    }
+}
@@ -2,6 +2,7 @@ use colored::{ColoredString, Colorize};
 use log::*;
 use std::env;
 use std::env::VarError;
+use std::error::Error;
 use std::fmt::{Debug, Display, Formatter, Result as FmtResult};
 use url::Url;
 
@@ -32,110 +33,331 @@ impl SecureString {
     }
 }
 
+impl From<String> for SecureString {
+    fn from(value: String) -> Self {
+        SecureString(value)
+    }
+}
+
+/// A setting which could not be loaded
+#[derive(Debug)]
+pub enum SettingError {
+    Missing {
+        name: String,
+    },
+    NotUnicode {
+        name: String,
+    },
+    InvalidUrl {
+        name: String,
+        value: String,
+        reason: String,
+    },
+    /// any other value which could not be parsed, e.g. a cache lifetime
+    Invalid {
+        name: String,
+        reason: String,
+    },
+}
+
+pub type SettingResult<T> = std::result::Result<T, SettingError>;
+
+impl Display for SettingError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            SettingError::Missing { name } => write!(
+                f,
+                "Setting {} is not present. Set it as environment variable, add it to the .env file, e.g. {}=\"value\", or to the profile",
+                name, name
+            ),
+            SettingError::NotUnicode { name } => {
+                write!(f, "Setting {} is not a valid utf8 string", name)
+            }
+            SettingError::InvalidUrl {
+                name,
+                value,
+                reason,
+            } => write!(
+                f,
+                "Setting {} is not a valid url: {}. Value: {}",
+                name, reason, value
+            ),
+            SettingError::Invalid { name, reason } => {
+                write!(f, "Setting {} is invalid: {}", name, reason)
+            }
+        }
+    }
+}
+
+impl Error for SettingError {}
+
+/// All problems found by a [`Validation`]
+#[derive(Debug)]
+pub struct SettingErrors(pub Vec<SettingError>);
+
+impl Display for SettingErrors {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self.0.as_slice() {
+            [error] => write!(f, "{}", error),
+            errors => {
+                write!(f, "{} settings are invalid:", errors.len())?;
+                for error in errors.iter() {
+                    write!(f, "\n  {}", error)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Error for SettingErrors {}
+
+/// Collects the problems of several settings, so all of them can be reported at once
+#[derive(Default)]
+pub struct Validation {
+    errors: Vec<SettingError>,
+}
+
+impl Validation {
+    /// Returns the value or records the error
+    pub fn check<T>(&mut self, result: SettingResult<T>) -> Option<T> {
+        match result {
+            Ok(value) => Some(value),
+            Err(err) => {
+                self.errors.push(err);
+                None
+            }
+        }
+    }
+
+    /// Records a missing setting if it was loaded without errors but has no value
+    pub fn require<T>(&mut self, setting_name: &str, value: Option<Option<T>>) -> Option<T> {
+        if let Some(None) = value {
+            self.errors.push(SettingError::Missing {
+                name: setting_name.to_string(),
+            });
+        }
+        value.flatten()
+    }
+
+    /// Fails with all recorded problems. Otherwise returns the required values, which are only
+    /// `None` if [`Validation::require`] recorded them as missing.
+    pub fn finish<T>(self, values: Option<T>) -> std::result::Result<T, SettingErrors> {
+        match values {
+            Some(values) if self.errors.is_empty() => Ok(values),
+            _ => Err(SettingErrors(self.errors)),
+        }
+    }
+}
+
 /// Loads a required setting entry as string from the environment
-///
-/// Panics if not present.
-pub fn require_string<S: AsRef<str>>(setting_name: S) -> String {
+pub fn require_string<S: AsRef<str>>(setting_name: S) -> SettingResult<String> {
     let setting_name = setting_name.as_ref();
-    let setting_value = load_setting(setting_name);
+    let setting_value = load_setting(setting_name)?;
     info!(
         "Successfully load {} with value {}",
         setting_name.blue(),
         setting_value.blue()
     );
-    setting_value
+    Ok(setting_value)
 }
 
-pub fn optional_string<S: AsRef<str>>(setting_name: S) -> Option<String> {
+pub fn optional_string<S: AsRef<str>>(setting_name: S) -> SettingResult<Option<String>> {
     let setting_name = setting_name.as_ref();
-    let setting_value = try_load_setting(setting_name);
-    setting_value
+    try_load_setting(setting_name)
 }
 
 /// Loads a required setting entry as SecureString from the environment
-///
-/// Panics if not present.
-pub fn require_secure_string<S: AsRef<str>>(setting_name: S) -> SecureString {
+pub fn require_secure_string<S: AsRef<str>>(setting_name: S) -> SettingResult<SecureString> {
     let setting_name = setting_name.as_ref();
-    let setting_value = SecureString(load_setting(setting_name));
+    let setting_value = SecureString(load_setting(setting_name)?);
     info!(
         "Successfully load {} with value {}",
         setting_name.blue(),
         setting_value.blue()
     );
-    setting_value
+    Ok(setting_value)
+}
+
+/// Loads an optional setting entry as SecureString from the environment
+pub fn optional_secure_string<S: AsRef<str>>(
+    setting_name: S,
+) -> SettingResult<Option<SecureString>> {
+    Ok(try_load_setting(setting_name.as_ref())?.map(SecureString))
 }
 
 /// Loads an optional setting and if present converts into an url.
 ///
 /// returns `None` if the setting is not present.
-/// panics if the url conversion was unsuccessful
-pub fn optional_url<S: AsRef<str>>(setting_name: S) -> Option<Url> {
+pub fn optional_url<S: AsRef<str>>(setting_name: S) -> SettingResult<Option<Url>> {
     let setting_name = setting_name.as_ref();
-    try_load_setting(setting_name)
-        .map(|url_string| {
-            match url_string.parse::<Url>() {
-                Ok(result) => {
-                    info!(
-                        "Successfully load url {} with value {}",
-                        setting_name.blue(),
-                        url_string.to_string().blue()
-                    );
-                    result
-                }
-                Err(err) => {
-                    eprintln!(
-                        "Could not parse setting {}. It is present but not a valid URL. Value: {}. Error: {}",
-                        setting_name.blue(),
-                        url_string.blue(),
-                        err.to_string().red()
-                    );
-                    std::process::exit(1);
-                }
-            }
-        })
+    try_load_setting(setting_name)?
+        .map(|url_string| parse_url(setting_name, url_string.as_str()))
+        .transpose()
 }
+
 /// Loads a required setting entry as url from the environment
-///
-/// Panics if not present or not a valid url.
-pub fn require_url<S: AsRef<str>>(setting_name: S) -> Url {
+pub fn require_url<S: AsRef<str>>(setting_name: S) -> SettingResult<Url> {
     let setting_name = setting_name.as_ref();
-    optional_url(setting_name).expect(format!("Could not load setting {}", setting_name).as_str())
+    optional_url(setting_name)?.ok_or_else(|| SettingError::Missing {
+        name: setting_name.to_string(),
+    })
 }
 
-fn try_load_setting(setting_name: &str) -> Option<String> {
+/// Parses the value of a setting as url, the name is only used for logging and errors
+pub fn parse_url(setting_name: &str, url_string: &str) -> SettingResult<Url> {
+    match url_string.parse::<Url>() {
+        Ok(result) => {
+            info!(
+                "Successfully load url {} with value {}",
+                setting_name.blue(),
+                url_string.to_string().blue()
+            );
+            Ok(result)
+        }
+        Err(err) => Err(SettingError::InvalidUrl {
+            name: setting_name.to_string(),
+            value: url_string.to_string(),
+            reason: err.to_string(),
+        }),
+    }
+}
+
+fn try_load_setting(setting_name: &str) -> SettingResult<Option<String>> {
     debug!("Load setting {}", setting_name);
     match env::var(setting_name) {
-        Ok(result) => Some(result),
-        Err(err) => match err {
-            VarError::NotPresent => None,
-            VarError::NotUnicode(_) => {
-                eprintln!(
-                    "Could not load setting {} because it is not a valid utf8 string.",
-                    setting_name
-                );
-                std::process::exit(1);
-            }
-        },
+        Ok(result) => Ok(Some(result)),
+        Err(VarError::NotPresent) => Ok(None),
+        Err(VarError::NotUnicode(_)) => Err(SettingError::NotUnicode {
+            name: setting_name.to_string(),
+        }),
     }
 }
 
 /// Loads a required setting entry from the environment
-///
-/// Panics if not present.
-fn load_setting(setting_name: &str) -> String {
-    debug!("Load setting {}", setting_name);
-    match try_load_setting(setting_name) {
-        Some(value) => value,
-        None => {
-            eprintln!(
-                r#"ERROR: Could not load setting {} because it is not present.
-Please set it as environment variable or add it to the .env file. E.g.:
-    {}="value""#,
-                setting_name.blue(),
-                setting_name
-            );
-            std::process::exit(1);
-        }
+fn load_setting(setting_name: &str) -> SettingResult<String> {
+    try_load_setting(setting_name)?.ok_or_else(|| SettingError::Missing {
+        name: setting_name.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn collects_all_problems() {
+        let mut validation = Validation::default();
+        assert_eq!(validation.check(Ok::<_, SettingError>(1)), Some(1));
+        assert_eq!(
+            validation.check::<u32>(Err(SettingError::NotUnicode {
+                name: "A".to_string()
+            })),
+            None
+        );
+        // a value which failed to load is not missing as well
+        assert_eq!(validation.require::<u32>("A", None), None);
+        assert_eq!(validation.require("B", Some(None::<u32>)), None);
+        assert_eq!(validation.require("C", Some(Some(3))), Some(3));
+
+        let errors = validation.finish(Some(())).unwrap_err();
+        assert_eq!(
+            errors.to_string(),
+            "2 settings are invalid:\n  Setting A is not a valid utf8 string\n  Setting B is not present. Set it as environment variable, add it to the .env file, e.g. B=\"value\", or to the profile"
+        );
+    }
+
+    #[test]
+    fn finishes_without_problems() {
+        let mut validation = Validation::default();
+        let value = validation.require("A", Some(Some("value")));
+        assert_eq!(validation.finish(value).unwrap(), "value");
+    }
+
+    #[test]
+    fn describes_setting_errors() {
+        let invalid = SettingError::Invalid {
+            name: "PLEASANT_PASSWORD_CACHE_TTL".to_string(),
+            reason: "unknown unit".to_string(),
+        };
+        assert_eq!(
+            invalid.to_string(),
+            "Setting PLEASANT_PASSWORD_CACHE_TTL is invalid: unknown unit"
+        );
+        assert_eq!(
+            SettingErrors(vec![invalid]).to_string(),
+            "Setting PLEASANT_PASSWORD_CACHE_TTL is invalid: unknown unit"
+        );
+        assert_eq!(
+            parse_url("profile url", "no url").unwrap_err().to_string(),
+            "Setting profile url is not a valid url: relative URL without a base. Value: no url"
+        );
+    }
+
+    #[test]
+    fn loads_settings_from_the_environment() {
+        env::set_var("PLEASANT_PASSWORD_TEST_STRING", "value");
+        env::set_var("PLEASANT_PASSWORD_TEST_URL", "https://example.com/pleasant");
+        env::set_var("PLEASANT_PASSWORD_TEST_INVALID_URL", "example");
+        env::remove_var("PLEASANT_PASSWORD_TEST_UNSET");
+
+        assert_eq!(
+            optional_string("PLEASANT_PASSWORD_TEST_STRING").unwrap(),
+            Some("value".to_string())
+        );
+        assert_eq!(
+            optional_string("PLEASANT_PASSWORD_TEST_UNSET").unwrap(),
+            None
+        );
+        assert_eq!(
+            require_string("PLEASANT_PASSWORD_TEST_STRING").unwrap(),
+            "value"
+        );
+        assert!(matches!(
+            require_string("PLEASANT_PASSWORD_TEST_UNSET"),
+            Err(SettingError::Missing { name }) if name == "PLEASANT_PASSWORD_TEST_UNSET"
+        ));
+        assert_eq!(
+            require_secure_string("PLEASANT_PASSWORD_TEST_STRING")
+                .unwrap()
+                .as_str(),
+            "value"
+        );
+        assert!(optional_secure_string("PLEASANT_PASSWORD_TEST_UNSET")
+            .unwrap()
+            .is_none());
+
+        assert_eq!(
+            require_url("PLEASANT_PASSWORD_TEST_URL").unwrap().as_str(),
+            "https://example.com/pleasant"
+        );
+        assert_eq!(optional_url("PLEASANT_PASSWORD_TEST_UNSET").unwrap(), None);
+        assert!(matches!(
+            optional_url("PLEASANT_PASSWORD_TEST_INVALID_URL"),
+            Err(SettingError::InvalidUrl { value, .. }) if value == "example"
+        ));
+    }
+
+    #[test]
+    fn rejects_settings_which_are_no_unicode() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        env::set_var(
+            "PLEASANT_PASSWORD_TEST_NOT_UNICODE",
+            OsStr::from_bytes(b"\xff\xfe"),
+        );
+        assert!(matches!(
+            optional_string("PLEASANT_PASSWORD_TEST_NOT_UNICODE"),
+            Err(SettingError::NotUnicode { .. })
+        ));
+    }
+
+    #[test]
+    fn masks_secure_strings() {
+        let secret = SecureString::from("hunter2".to_string());
+        assert_eq!(secret.to_string(), "[MASKED]");
+        assert_eq!(format!("{:?}", secret), "[MASKED]");
+        assert_eq!(secret.as_str(), "hunter2");
     }
 }
@@ -0,0 +1,282 @@
+//! Templates with placeholders for entry fields, e.g. for config files which embed passwords.
+//!
+//! A placeholder is either `{{ pleasant "<entry-id>" "<field>" }}` or
+//! `{{ pleasant_query "<query>" "<field>" }}`. The field is one of `password`, `username`,
+//! `name`, `notes` or `id` and defaults to `password`. Any other `{{ ... }}`, e.g. of a template
+//! engine which processes the result, is kept as it is.
+
+use std::error::Error;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+enum Part {
+    Text(String),
+    Placeholder(Placeholder),
+}
+
+#[derive(Debug)]
+pub struct Placeholder {
+    /// 1-based line of the placeholder in the template
+    pub line: usize,
+    pub lookup: Lookup,
+    pub field: Field,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Lookup {
+    Entry(String),
+    Query(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Field {
+    Password,
+    Username,
+    Name,
+    Notes,
+    Id,
+}
+
+#[derive(Debug)]
+pub struct TemplateError {
+    line: usize,
+    message: String,
+}
+
+impl Display for TemplateError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(
+            f,
+            "Invalid template in line {}: {}",
+            self.line, self.message
+        )
+    }
+}
+
+impl Error for TemplateError {}
+
+impl Display for Lookup {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Lookup::Entry(entry_id) => write!(f, "pleasant \"{}\"", entry_id),
+            Lookup::Query(query) => write!(f, "pleasant_query \"{}\"", query),
+        }
+    }
+}
+
+impl Template {
+    pub fn parse(input: &str) -> Result<Template, TemplateError> {
+        let mut parts = Vec::new();
+        let mut rest = input;
+        let mut line = 1;
+        while let Some(start) = rest.find("{{") {
+            let text = &rest[..start];
+            line += text.matches('\n').count();
+            parts.push(Part::Text(text.to_string()));
+
+            let end = match rest[start..].find("}}") {
+                Some(end) => end,
+                None if function_of(&rest[start + 2..]).is_some() => {
+                    return Err(TemplateError {
+                        line,
+                        message: "unterminated placeholder, missing }}".to_string(),
+                    })
+                }
+                None => {
+                    rest = &rest[start..];
+                    break;
+                }
+            };
+            let expression = &rest[start + 2..start + end];
+            parts.push(match parse_placeholder(expression, line)? {
+                Some(placeholder) => Part::Placeholder(placeholder),
+                None => Part::Text(rest[start..start + end + 2].to_string()),
+            });
+            line += expression.matches('\n').count();
+            rest = &rest[start + end + 2..];
+        }
+        parts.push(Part::Text(rest.to_string()));
+        Ok(Template { parts })
+    }
+
+    pub fn placeholders(&self) -> impl Iterator<Item = &Placeholder> {
+        self.parts.iter().filter_map(|part| match part {
+            Part::Placeholder(placeholder) => Some(placeholder),
+            Part::Text(_) => None,
+        })
+    }
+
+    /// Replaces the placeholders with the values, given in the order of [`Template::placeholders`]
+    pub fn render(&self, values: &[String]) -> String {
+        let mut values = values.iter();
+        let mut result = String::new();
+        for part in self.parts.iter() {
+            match part {
+                Part::Text(text) => result.push_str(text),
+                Part::Placeholder(_) => {
+                    result.push_str(values.next().expect("a value for every placeholder"))
+                }
+            }
+        }
+        result
+    }
+}
+
+/// The lookup function an expression calls, `None` if it is no placeholder of this client
+fn function_of(expression: &str) -> Option<&str> {
+    let expression = expression.trim_start();
+    let end = expression
+        .find(|c: char| c.is_whitespace() || c == '"')
+        .unwrap_or(expression.len());
+    match &expression[..end] {
+        function @ "pleasant" | function @ "pleasant_query" => Some(function),
+        _ => None,
+    }
+}
+
+/// Parses the expression between the braces. `None` if it is no placeholder of this client.
+fn parse_placeholder(expression: &str, line: usize) -> Result<Option<Placeholder>, TemplateError> {
+    let error = |message: String| TemplateError { line, message };
+
+    let function = match function_of(expression) {
+        Some(function) => function,
+        None => return Ok(None),
+    };
+    let arguments = &expression.trim_start()[function.len()..];
+    let arguments = parse_arguments(arguments).map_err(error)?;
+
+    let (reference, field) = match arguments.as_slice() {
+        [reference] => (reference.clone(), Field::Password),
+        [reference, field] => (reference.clone(), parse_field(field).map_err(error)?),
+        _ => {
+            return Err(error(format!(
+                "expected {} \"<entry>\" \"<field>\" but got {} arguments",
+                function,
+                arguments.len()
+            )))
+        }
+    };
+    let lookup = match function {
+        "pleasant" => Lookup::Entry(reference),
+        _ => Lookup::Query(reference),
+    };
+    Ok(Some(Placeholder {
+        line,
+        lookup,
+        field,
+    }))
+}
+
+/// Parses a list of double quoted strings
+fn parse_arguments(input: &str) -> Result<Vec<String>, String> {
+    let mut arguments = Vec::new();
+    let mut chars = input.trim().chars();
+    while let Some(c) = chars.next() {
+        if c.is_whitespace() {
+            continue;
+        }
+        if c != '"' {
+            return Err(format!("expected a double quoted string but got '{}'", c));
+        }
+        let mut argument = String::new();
+        loop {
+            match chars.next() {
+                None => return Err("unterminated quote".to_string()),
+                Some('"') => break,
+                Some('\\') => argument.extend(chars.next()),
+                Some(c) => argument.push(c),
+            }
+        }
+        arguments.push(argument);
+    }
+    Ok(arguments)
+}
+
+fn parse_field(field: &str) -> Result<Field, String> {
+    match field {
+        "password" => Ok(Field::Password),
+        "username" => Ok(Field::Username),
+        "name" => Ok(Field::Name),
+        "notes" => Ok(Field::Notes),
+        "id" => Ok(Field::Id),
+        _ => Err(format!(
+            "unknown field '{}', expected one of password, username, name, notes, id",
+            field
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lookups(template: &Template) -> Vec<(usize, Lookup, Field)> {
+        template
+            .placeholders()
+            .map(|placeholder| {
+                (
+                    placeholder.line,
+                    placeholder.lookup.clone(),
+                    placeholder.field,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn parses_placeholders() {
+        let template = Template::parse(
+            "user: {{ pleasant_query \"Prod DB\" \"username\" }}\npassword: {{pleasant \"a-1\"}}\n",
+        )
+        .unwrap();
+        assert_eq!(
+            lookups(&template),
+            vec![
+                (1, Lookup::Query("Prod DB".to_string()), Field::Username),
+                (2, Lookup::Entry("a-1".to_string()), Field::Password),
+            ]
+        );
+        assert_eq!(
+            template.render(&["admin".to_string(), "s3cret".to_string()]),
+            "user: admin\npassword: s3cret\n"
+        );
+    }
+
+    #[test]
+    fn keeps_other_expressions() {
+        let input = "image: {{ .Values.image }}\n{{- if .Values.db }}\npassword: {{ pleasant \"a-1\" }}\n{{ end }} {{";
+        let template = Template::parse(input).unwrap();
+        assert_eq!(
+            lookups(&template),
+            vec![(3, Lookup::Entry("a-1".to_string()), Field::Password)]
+        );
+        assert_eq!(
+            template.render(&["s3cret".to_string()]),
+            input.replace("{{ pleasant \"a-1\" }}", "s3cret")
+        );
+    }
+
+    #[test]
+    fn reports_invalid_placeholders() {
+        let error = |input: &str| Template::parse(input).err().unwrap().to_string();
+        assert_eq!(
+            error("a\n{{ pleasant \"a-1\""),
+            "Invalid template in line 2: unterminated placeholder, missing }}"
+        );
+        assert_eq!(
+            error("{{ pleasant \"a-1\" \"pin\" }}"),
+            "Invalid template in line 1: unknown field 'pin', expected one of password, username, name, notes, id"
+        );
+        assert_eq!(
+            error("{{ pleasant }}"),
+            "Invalid template in line 1: expected pleasant \"<entry>\" \"<field>\" but got 0 arguments"
+        );
+        assert_eq!(
+            error("{{ pleasant_query a }}"),
+            "Invalid template in line 1: expected a double quoted string but got 'a'"
+        );
+    }
+}
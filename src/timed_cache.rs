@@ -1,38 +1,131 @@
-use crate::types::Result;
+use crate::settings::SecureString;
+use argon2::Argon2;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
 use chrono::prelude::*;
 use chrono::Duration;
 use log::*;
+use rand_core::RngCore;
 use rusqlite::{params, Connection, OptionalExtension};
-use std::ops::Add;
-use std::path::Path;
+use serde::Serialize;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 
+/// Length of the ChaCha20-Poly1305 key in bytes
+const KEY_LENGTH: usize = 32;
+const NONCE_LENGTH: usize = 12;
+/// Encrypted with the cache key to detect a wrong key before anything is decrypted
+const KEY_CHECK: &str = "pleasant password client cache";
+
+/// A failure of the cache, e.g. a wrong key or a broken cache database
+#[derive(Debug)]
+pub struct CacheError(String);
+
+type Result<T> = std::result::Result<T, CacheError>;
+
+impl Display for CacheError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for CacheError {}
+
+macro_rules! cache_error_from {
+    ($($error:ty),*) => {
+        $(
+            impl From<$error> for CacheError {
+                fn from(err: $error) -> Self {
+                    CacheError(err.to_string())
+                }
+            }
+        )*
+    };
+}
+
+cache_error_from!(
+    rusqlite::Error,
+    std::io::Error,
+    base64::DecodeError,
+    std::string::FromUtf8Error,
+    String,
+    &str
+);
+
+/// Source of the key the cached values are encrypted with
+pub enum CacheKey {
+    /// A secret of the user. The key is derived with argon2 and a random salt stored in the cache.
+    Secret(SecureString),
+    /// A file with a random key. It is created, readable only by the user, if it does not exist.
+    KeyFile(PathBuf),
+}
+
+/// A cache entry without its value
+#[derive(Debug, Serialize)]
+pub struct CacheEntry {
+    pub key: String,
+    pub valid_until: DateTime<Utc>,
+    pub expired: bool,
+}
+
+/// A key value store whose entries expire.
+///
+/// Values are encrypted with ChaCha20-Poly1305 and only decrypted in memory. The key of an entry
+/// is used as associated data, so encrypted values cannot be swapped between keys.
 pub struct TimedCache {
     connection: Connection,
+    cipher: ChaCha20Poly1305,
 }
 
 impl TimedCache {
-    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+    pub fn open<P: AsRef<Path>>(path: P, cache_key: &CacheKey) -> Result<Self> {
         let path = path.as_ref();
         debug!("Open cache at {}", path.display());
         let conn = Connection::open(path)?;
         debug!("Init cache database");
-        conn.execute(
+        conn.execute_batch(
             "CREATE TABLE IF NOT EXISTS timed_cache (
                    key TEXT PRIMARY KEY,
                    value TEXT,
                    valid_until TEXT
-        )",
-            params![],
+            );
+            CREATE TABLE IF NOT EXISTS cache_meta (
+                   key TEXT PRIMARY KEY,
+                   value TEXT NOT NULL
+            );",
         )?;
-        Ok(TimedCache { connection: conn })
+
+        let key = TimedCache::load_key(&conn, cache_key)?;
+        let cache = TimedCache {
+            connection: conn,
+            cipher: ChaCha20Poly1305::new_from_slice(&key)
+                .map_err(|_| "Invalid cache key length")?,
+        };
+
+        match cache.meta("key_check")? {
+            Some(check) => {
+                if cache.decrypt("key_check", check.as_str()).is_err() {
+                    return Err(format!(
+                        "The cache {} was encrypted with a different key. Use the secret or key file it was created with or delete the cache.",
+                        path.display()
+                    )
+                    .into());
+                }
+            }
+            None => cache.encrypt_plaintext_cache()?,
+        }
+        Ok(cache)
     }
 
     pub fn put(&self, key: &str, value: &str, duration: i64) -> Result<()> {
-        let valid_until = TimedCache::calc_date(duration);
+        let valid_until = TimedCache::calc_date(duration)?;
         debug!(
             "Storing new value for key {} valid until: {}",
             key, valid_until
         );
+        let value = self.encrypt(key, value)?;
         self.connection.execute(
             "REPLACE INTO timed_cache VALUES (?1,?2,?3)",
             params![key, value, valid_until],
@@ -64,7 +157,7 @@ impl TimedCache {
             Some((key, value, valid_until)) => {
                 if valid_until >= Utc::now() {
                     debug!("HIT. Entry found for {}", key);
-                    Ok(Some(value))
+                    Ok(Some(self.decrypt(key.as_str(), value.as_str())?))
                 } else {
                     debug!(
                         "MISS. An entry found for {} but it is expired ({})",
@@ -76,11 +169,403 @@ impl TimedCache {
         }
     }
 
-    pub fn del(&self, key: &str) -> String {
-        unimplemented!("del")
+    /// Deletes the entry of the key. Returns false if there was none.
+    pub fn del(&self, key: &str) -> Result<bool> {
+        debug!("Delete entry for key {}", key);
+        let deleted = self
+            .connection
+            .execute("DELETE FROM timed_cache WHERE key = ?1", params![key])?;
+        Ok(deleted > 0)
+    }
+
+    /// Deletes all entries and returns their number
+    pub fn clear(&self) -> Result<usize> {
+        debug!("Delete all entries");
+        Ok(self
+            .connection
+            .execute("DELETE FROM timed_cache", params![])?)
+    }
+
+    /// Deletes all expired entries and returns their number
+    pub fn purge_expired(&self) -> Result<usize> {
+        let purged = self.connection.execute(
+            "DELETE FROM timed_cache WHERE valid_until < ?1",
+            params![Utc::now()],
+        )?;
+        debug!("Purged {} expired entries", purged);
+        Ok(purged)
+    }
+
+    /// Lists the keys and expiry dates of all entries. Values are not decrypted.
+    pub fn list(&self) -> Result<Vec<CacheEntry>> {
+        let mut stmt = self
+            .connection
+            .prepare("SELECT key, valid_until FROM timed_cache ORDER BY valid_until")?;
+        let mut rows = stmt.query(params![])?;
+
+        let now = Utc::now();
+        let mut result = Vec::new();
+        while let Some(row) = rows.next()? {
+            let valid_until: DateTime<Utc> = row.get(1)?;
+            result.push(CacheEntry {
+                key: row.get(0)?,
+                valid_until,
+                expired: valid_until < now,
+            });
+        }
+        Ok(result)
+    }
+
+    fn load_key(conn: &Connection, cache_key: &CacheKey) -> Result<Vec<u8>> {
+        match cache_key {
+            CacheKey::KeyFile(path) => {
+                let mut key = vec![0u8; KEY_LENGTH];
+                OsRng.fill_bytes(&mut key);
+                // a concurrent process may create the file at the same time, the first one wins
+                match TimedCache::create_key_file(path, key.as_slice()) {
+                    Ok(()) => {
+                        info!("Created new cache key file {}", path.display());
+                        Ok(key)
+                    }
+                    Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => {
+                        debug!("Read cache key from {}", path.display());
+                        let key = std::fs::read(path)?;
+                        if key.len() != KEY_LENGTH {
+                            return Err(format!(
+                                "The cache key file {} must contain exactly {} bytes",
+                                path.display(),
+                                KEY_LENGTH
+                            )
+                            .into());
+                        }
+                        Ok(key)
+                    }
+                    Err(err) => Err(err.into()),
+                }
+            }
+            CacheKey::Secret(secret) => {
+                let salt: Option<String> = conn
+                    .query_row(
+                        "SELECT value FROM cache_meta WHERE key = 'salt'",
+                        params![],
+                        |row| row.get(0),
+                    )
+                    .optional()?;
+                let salt = match salt {
+                    Some(salt) => base64::decode(salt)?,
+                    None => {
+                        let mut salt = vec![0u8; 16];
+                        OsRng.fill_bytes(&mut salt);
+                        conn.execute(
+                            "INSERT INTO cache_meta VALUES ('salt', ?1)",
+                            params![base64::encode(&salt)],
+                        )?;
+                        salt
+                    }
+                };
+                debug!("Derive cache key from secret");
+                let mut key = vec![0u8; KEY_LENGTH];
+                Argon2::default()
+                    .hash_password_into(secret.as_str().as_bytes(), &salt, &mut key)
+                    .map_err(|err| format!("Could not derive the cache key: {}", err))?;
+                Ok(key)
+            }
+        }
+    }
+
+    /// Writes the key to a new file only readable by the user, fails if the file exists
+    fn create_key_file(path: &Path, key: &[u8]) -> std::io::Result<()> {
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        options.open(path)?.write_all(key)
+    }
+
+    /// Encrypts the values of caches written before encryption was introduced
+    fn encrypt_plaintext_cache(&self) -> Result<()> {
+        let transaction = self.connection.unchecked_transaction()?;
+        let entries: Vec<(String, String)> = {
+            let mut stmt = self
+                .connection
+                .prepare("SELECT key, value FROM timed_cache")?;
+            let rows = stmt.query_map(params![], |row| Ok((row.get(0)?, row.get(1)?)))?;
+            rows.collect::<std::result::Result<_, _>>()?
+        };
+        if !entries.is_empty() {
+            info!("Encrypt {} plaintext cache entries", entries.len());
+        }
+        for (key, value) in entries.iter() {
+            self.connection.execute(
+                "UPDATE timed_cache SET value = ?1 WHERE key = ?2",
+                params![self.encrypt(key, value)?, key],
+            )?;
+        }
+        self.connection.execute(
+            "REPLACE INTO cache_meta VALUES ('key_check', ?1)",
+            params![self.encrypt("key_check", KEY_CHECK)?],
+        )?;
+        transaction.commit()?;
+        if !entries.is_empty() {
+            // the plaintext values could otherwise remain in free pages of the database file
+            self.connection.execute_batch("VACUUM")?;
+        }
+        Ok(())
+    }
+
+    fn meta(&self, key: &str) -> Result<Option<String>> {
+        Ok(self
+            .connection
+            .query_row(
+                "SELECT value FROM cache_meta WHERE key = ?1",
+                params![key],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+
+    /// Returns base64(nonce || ciphertext)
+    fn encrypt(&self, key: &str, value: &str) -> Result<String> {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: value.as_bytes(),
+                    aad: key.as_bytes(),
+                },
+            )
+            .map_err(|_| "Could not encrypt cache value")?;
+        let mut data = nonce.to_vec();
+        data.extend(ciphertext);
+        Ok(base64::encode(data))
+    }
+
+    fn decrypt(&self, key: &str, value: &str) -> Result<String> {
+        let data = base64::decode(value)?;
+        if data.len() < NONCE_LENGTH {
+            return Err(format!("The cached value of {} is corrupt", key).into());
+        }
+        let (nonce, ciphertext) = data.split_at(NONCE_LENGTH);
+        let plaintext = self
+            .cipher
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: key.as_bytes(),
+                },
+            )
+            .map_err(|_| format!("Could not decrypt the cached value of {}", key))?;
+        Ok(String::from_utf8(plaintext)?)
+    }
+
+    /// The expiry date of a value stored now. Fails instead of overflowing for lifetimes no date
+    /// can hold.
+    fn calc_date(duration: i64) -> Result<DateTime<Utc>> {
+        let too_long = || CacheError(format!("The lifetime of {} seconds is too long", duration));
+        let max = Duration::max_value().num_seconds();
+        if !(-max..=max).contains(&duration) {
+            return Err(too_long());
+        }
+        Utc::now()
+            .checked_add_signed(Duration::seconds(duration))
+            .ok_or_else(too_long)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache_policy::MAX_TTL;
+
+    /// A cache in a fresh directory, which is removed when the test is done
+    struct TestCache {
+        dir: PathBuf,
+    }
+
+    impl TestCache {
+        fn new(name: &str) -> TestCache {
+            let dir = std::env::temp_dir().join(format!(
+                "pleasant-cache-{}-{}",
+                name,
+                std::process::id()
+            ));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            TestCache { dir }
+        }
+
+        fn path(&self, file: &str) -> PathBuf {
+            self.dir.join(file)
+        }
+
+        fn open(&self, cache_key: &CacheKey) -> Result<TimedCache> {
+            TimedCache::open(self.path("cache"), cache_key)
+        }
+    }
+
+    impl Drop for TestCache {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    fn secret(value: &str) -> CacheKey {
+        CacheKey::Secret(value.to_string().into())
+    }
+
+    #[test]
+    fn rejects_lifetimes_beyond_the_last_date() {
+        let test = TestCache::new("overflow");
+        let cache = test.open(&secret("s3cret")).unwrap();
+        for duration in [i64::MAX, i64::MIN, Duration::max_value().num_seconds()].iter() {
+            assert!(cache.put("ACCESS_TOKEN", "token", *duration).is_err());
+        }
+        assert_eq!(cache.get("ACCESS_TOKEN").unwrap(), None);
+
+        cache.put("ACCESS_TOKEN", "token", MAX_TTL).unwrap();
+        assert_eq!(cache.get("ACCESS_TOKEN").unwrap().as_deref(), Some("token"));
+    }
+
+    #[test]
+    fn round_trips_values_until_they_expire() {
+        let test = TestCache::new("round-trip");
+        let cache = test.open(&secret("s3cret")).unwrap();
+        cache.put("94153de4", "password", 60).unwrap();
+        cache.put("ACCESS_TOKEN", "token", -1).unwrap();
+
+        assert_eq!(cache.get("94153de4").unwrap().as_deref(), Some("password"));
+        assert_eq!(cache.get("ACCESS_TOKEN").unwrap(), None);
+        assert_eq!(cache.get("REFRESH_TOKEN").unwrap(), None);
+        let expired: Vec<(String, bool)> = cache
+            .list()
+            .unwrap()
+            .into_iter()
+            .map(|entry| (entry.key, entry.expired))
+            .collect();
+        assert_eq!(
+            expired,
+            vec![
+                ("ACCESS_TOKEN".to_string(), true),
+                ("94153de4".to_string(), false)
+            ]
+        );
+        assert_eq!(cache.purge_expired().unwrap(), 1);
+
+        // the key is derived again from the secret and the stored salt
+        drop(cache);
+        let cache = test.open(&secret("s3cret")).unwrap();
+        assert_eq!(cache.get("94153de4").unwrap().as_deref(), Some("password"));
+        assert!(cache.del("94153de4").unwrap());
+        assert!(!cache.del("94153de4").unwrap());
     }
 
-    fn calc_date(duration: i64) -> DateTime<Utc> {
-        Utc::now().add(Duration::seconds(duration))
+    #[test]
+    fn refuses_a_different_key() {
+        let test = TestCache::new("different-key");
+        let cache = test.open(&secret("s3cret")).unwrap();
+        cache.put("94153de4", "password", 60).unwrap();
+        drop(cache);
+
+        let different_keys = [secret("guessed"), CacheKey::KeyFile(test.path("cache.key"))];
+        for cache_key in different_keys.iter() {
+            let err = test.open(cache_key).err().unwrap();
+            assert!(
+                err.to_string()
+                    .contains("was encrypted with a different key"),
+                "{}",
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn encrypts_a_plaintext_cache() {
+        let test = TestCache::new("plaintext");
+        let connection = Connection::open(test.path("cache")).unwrap();
+        connection
+            .execute_batch(
+                "CREATE TABLE timed_cache (key TEXT PRIMARY KEY, value TEXT, valid_until TEXT);",
+            )
+            .unwrap();
+        connection
+            .execute(
+                "INSERT INTO timed_cache VALUES ('94153de4', 'password', ?1)",
+                params![Utc::now() + Duration::hours(1)],
+            )
+            .unwrap();
+
+        let cache = test.open(&secret("s3cret")).unwrap();
+        assert_eq!(cache.get("94153de4").unwrap().as_deref(), Some("password"));
+        let stored: String = connection
+            .query_row(
+                "SELECT value FROM timed_cache WHERE key = '94153de4'",
+                params![],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_ne!(stored, "password");
+        assert!(cache.meta("key_check").unwrap().is_some());
+    }
+
+    #[test]
+    fn creates_the_key_file_only_once() {
+        let test = TestCache::new("key-file");
+        let key_file = test.path("cache.key");
+        let cache = test.open(&CacheKey::KeyFile(key_file.clone())).unwrap();
+        cache.put("94153de4", "password", 60).unwrap();
+        drop(cache);
+
+        let key = std::fs::read(&key_file).unwrap();
+        assert_eq!(key.len(), KEY_LENGTH);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&key_file).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        let err = TimedCache::create_key_file(&key_file, &[0u8; KEY_LENGTH]).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::AlreadyExists);
+        assert_eq!(std::fs::read(&key_file).unwrap(), key);
+
+        let cache = test.open(&CacheKey::KeyFile(key_file.clone())).unwrap();
+        assert_eq!(cache.get("94153de4").unwrap().as_deref(), Some("password"));
+        drop(cache);
+
+        std::fs::write(&key_file, &key[..KEY_LENGTH - 1]).unwrap();
+        let err = test.open(&CacheKey::KeyFile(key_file)).err().unwrap();
+        assert!(
+            err.to_string().contains("must contain exactly 32 bytes"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn binds_values_to_their_keys() {
+        let test = TestCache::new("swap");
+        let cache = test.open(&secret("s3cret")).unwrap();
+        cache.put("ACCESS_TOKEN", "token", 60).unwrap();
+        cache.put("94153de4", "password", 60).unwrap();
+
+        // an attacker with write access swaps the ciphertexts
+        cache
+            .connection
+            .execute_batch(
+                "UPDATE timed_cache SET value = (SELECT value FROM timed_cache WHERE key = 'ACCESS_TOKEN')
+                 WHERE key = '94153de4';",
+            )
+            .unwrap();
+        let err = cache.get("94153de4").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Could not decrypt the cached value of 94153de4"
+        );
+        assert_eq!(cache.get("ACCESS_TOKEN").unwrap().as_deref(), Some("token"));
     }
 }
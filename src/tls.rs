@@ -0,0 +1,309 @@
+//! TLS settings of the connection to the server: an extra CA bundle, a client certificate,
+//! a minimum TLS version and certificate pinning.
+//!
+//! Without any of them the default TLS backend of reqwest is used. Otherwise the connection is
+//! made with rustls, trusting the certificates of the system plus the CA bundle.
+
+use crate::error::Error;
+use crate::types::Result;
+use log::*;
+use p12_keystore::KeyStore;
+use rustls::internal::pemfile;
+use rustls::{
+    Certificate, ClientConfig, PrivateKey, ProtocolVersion, RootCertStore, ServerCertVerified,
+    ServerCertVerifier, TLSError, WebPKIVerifier,
+};
+use sha2::{Digest, Sha256};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+
+#[derive(Default)]
+pub struct TlsOptions {
+    /// PEM file with CA certificates trusted in addition to the ones of the system
+    pub ca_bundle: Option<PathBuf>,
+    pub client_certificate: Option<ClientCertificate>,
+    pub min_version: Option<TlsVersion>,
+    /// `sha256/<base64>` hashes of the SubjectPublicKeyInfo. The certificate of the server
+    /// itself has to have one of them, the rest of the chain is not considered.
+    pub pinned_keys: Vec<String>,
+}
+
+pub enum ClientCertificate {
+    /// PEM files of the certificate chain and the PKCS#8 or RSA private key
+    Pem { certificate: PathBuf, key: PathBuf },
+    /// a PKCS#12 file, also known as .p12 or .pfx
+    Pkcs12 { file: PathBuf, password: String },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TlsVersion {
+    Tls12,
+    Tls13,
+}
+
+impl FromStr for TlsVersion {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s
+            .trim()
+            .to_lowercase()
+            .trim_start_matches("tls")
+            .trim_start_matches('v')
+        {
+            "1.2" => Ok(TlsVersion::Tls12),
+            "1.3" => Ok(TlsVersion::Tls13),
+            "1.0" | "1.1" => Err(format!(
+                "TLS {} is not supported anymore, the minimum is 1.2",
+                s
+            )),
+            _ => Err(format!("Unknown TLS version {}, expected 1.2 or 1.3", s)),
+        }
+    }
+}
+
+impl TlsOptions {
+    pub fn is_empty(&self) -> bool {
+        self.ca_bundle.is_none()
+            && self.client_certificate.is_none()
+            && self.min_version.is_none()
+            && self.pinned_keys.is_empty()
+    }
+}
+
+/// Applies the options to a client builder
+pub fn configure(
+    builder: reqwest::ClientBuilder,
+    options: &TlsOptions,
+) -> Result<reqwest::ClientBuilder> {
+    if options.is_empty() {
+        return Ok(builder);
+    }
+    Ok(builder.use_preconfigured_tls(client_config(options)?))
+}
+
+fn client_config(options: &TlsOptions) -> Result<ClientConfig> {
+    let mut config = ClientConfig::new();
+    config.root_store = root_store(options)?;
+
+    if let Some(min_version) = options.min_version {
+        config.versions = match min_version {
+            TlsVersion::Tls12 => vec![ProtocolVersion::TLSv1_3, ProtocolVersion::TLSv1_2],
+            TlsVersion::Tls13 => vec![ProtocolVersion::TLSv1_3],
+        };
+    }
+
+    if let Some(client_certificate) = &options.client_certificate {
+        let (chain, key) = load_client_certificate(client_certificate)?;
+        config
+            .set_single_client_cert(chain, key)
+            .map_err(|err| Error::Other(format!("Invalid client certificate: {}", err)))?;
+    }
+
+    if !options.pinned_keys.is_empty() {
+        for pin in options.pinned_keys.iter() {
+            parse_pin(pin)?;
+        }
+        config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(PinningVerifier {
+                verifier: WebPKIVerifier::new(),
+                pinned_keys: options.pinned_keys.clone(),
+            }));
+    }
+    Ok(config)
+}
+
+fn root_store(options: &TlsOptions) -> Result<RootCertStore> {
+    let mut store = match rustls_native_certs::load_native_certs() {
+        Ok(store) => store,
+        Err((Some(store), err)) => {
+            warn!("Some system certificates could not be loaded: {}", err);
+            store
+        }
+        Err((None, err)) => {
+            warn!("The system certificates could not be loaded: {}", err);
+            RootCertStore::empty()
+        }
+    };
+
+    if let Some(ca_bundle) = &options.ca_bundle {
+        let content = read(ca_bundle)?;
+        let (added, invalid) = store
+            .add_pem_file(&mut content.as_slice())
+            .map_err(|_| invalid_pem(ca_bundle))?;
+        debug!(
+            "Added {} certificates of {}, {} were invalid",
+            added,
+            ca_bundle.display(),
+            invalid
+        );
+        if added == 0 {
+            return Err(Error::Other(format!(
+                "The CA bundle {} contains no valid certificate",
+                ca_bundle.display()
+            )));
+        }
+    }
+    Ok(store)
+}
+
+fn load_client_certificate(
+    client_certificate: &ClientCertificate,
+) -> Result<(Vec<Certificate>, PrivateKey)> {
+    match client_certificate {
+        ClientCertificate::Pem { certificate, key } => {
+            let chain = pemfile::certs(&mut BufReader::new(read(certificate)?.as_slice()))
+                .map_err(|_| invalid_pem(certificate))?;
+            if chain.is_empty() {
+                return Err(Error::Other(format!(
+                    "{} contains no certificate",
+                    certificate.display()
+                )));
+            }
+
+            let key_content = read(key)?;
+            let mut keys = pemfile::pkcs8_private_keys(&mut key_content.as_slice())
+                .map_err(|_| invalid_pem(key))?;
+            if keys.is_empty() {
+                keys = pemfile::rsa_private_keys(&mut key_content.as_slice())
+                    .map_err(|_| invalid_pem(key))?;
+            }
+            match keys.into_iter().next() {
+                Some(private_key) => Ok((chain, private_key)),
+                None => Err(Error::Other(format!(
+                    "{} contains no PKCS#8 or RSA private key",
+                    key.display()
+                ))),
+            }
+        }
+        ClientCertificate::Pkcs12 { file, password } => {
+            let key_store = KeyStore::from_pkcs12(read(file)?.as_slice(), password.as_str())
+                .map_err(|err| {
+                    Error::Other(format!("Could not read {}: {}", file.display(), err))
+                })?;
+            let (_, key_chain) = key_store.private_key_chain().ok_or_else(|| {
+                Error::Other(format!("{} contains no private key", file.display()))
+            })?;
+            let chain = key_chain
+                .chain()
+                .iter()
+                .map(|certificate| Certificate(certificate.as_der().to_vec()))
+                .collect();
+            Ok((chain, PrivateKey(key_chain.key().to_vec())))
+        }
+    }
+}
+
+/// Checks the format of a pinned key, `sha256/` followed by the base64 encoded hash
+pub fn parse_pin(value: &str) -> std::result::Result<String, String> {
+    let value = value.trim();
+    let valid = value
+        .strip_prefix("sha256/")
+        .and_then(|hash| base64::decode(hash).ok())
+        .map(|hash| hash.len() == 32)
+        .unwrap_or(false);
+    if valid {
+        Ok(value.to_string())
+    } else {
+        Err(format!(
+            "Invalid pinned key '{}', expected sha256/ followed by the base64 encoded SPKI hash",
+            value
+        ))
+    }
+}
+
+/// The pin of a DER encoded certificate as `sha256/<base64>`, the format of HPKP and curl
+pub fn spki_pin(certificate: &[u8]) -> Option<String> {
+    let spki = subject_public_key_info(certificate)?;
+    Some(format!(
+        "sha256/{}",
+        base64::encode(Sha256::digest(spki).as_slice())
+    ))
+}
+
+/// Verifies the chain as usual and additionally requires one of the pinned keys for the
+/// end-entity certificate. Further certificates are not checked: webpki does not tell which of
+/// them it used, so a server could append any public certificate to pass the pin.
+struct PinningVerifier {
+    verifier: WebPKIVerifier,
+    pinned_keys: Vec<String>,
+}
+
+impl ServerCertVerifier for PinningVerifier {
+    fn verify_server_cert(
+        &self,
+        roots: &RootCertStore,
+        presented_certs: &[Certificate],
+        dns_name: webpki::DNSNameRef,
+        ocsp_response: &[u8],
+    ) -> std::result::Result<ServerCertVerified, TLSError> {
+        let verified =
+            self.verifier
+                .verify_server_cert(roots, presented_certs, dns_name, ocsp_response)?;
+        let pinned = presented_certs
+            .first()
+            .and_then(|certificate| spki_pin(certificate.0.as_slice()))
+            .map(|pin| self.pinned_keys.contains(&pin))
+            .unwrap_or(false);
+        if pinned {
+            Ok(verified)
+        } else {
+            Err(TLSError::General(
+                "the server certificate matches none of the pinned keys".to_string(),
+            ))
+        }
+    }
+}
+
+/// Finds the SubjectPublicKeyInfo in the TBSCertificate of a DER encoded X.509 certificate
+fn subject_public_key_info(certificate: &[u8]) -> Option<&[u8]> {
+    let (_, certificate, _) = der_element(certificate)?;
+    let (_, tbs_certificate, _) = der_element(certificate)?;
+
+    let mut rest = tbs_certificate;
+    // the version is optional and tagged with [0]
+    if rest.first() == Some(&0xa0) {
+        rest = der_element(rest)?.2;
+    }
+    // serial number, signature algorithm, issuer, validity and subject
+    for _ in 0..5 {
+        rest = der_element(rest)?.2;
+    }
+    let (element, _, _) = der_element(rest)?;
+    Some(element)
+}
+
+/// Splits off the first DER element. Returns the whole element, its content and the rest.
+fn der_element(data: &[u8]) -> Option<(&[u8], &[u8], &[u8])> {
+    let first_length_byte = *data.get(1)?;
+    let (header_length, content_length) = if first_length_byte < 0x80 {
+        (2, first_length_byte as usize)
+    } else {
+        let length_bytes = (first_length_byte & 0x7f) as usize;
+        if length_bytes == 0 || length_bytes > 4 {
+            return None;
+        }
+        let length = data
+            .get(2..2 + length_bytes)?
+            .iter()
+            .fold(0usize, |length, byte| (length << 8) | *byte as usize);
+        (2 + length_bytes, length)
+    };
+    let end = header_length.checked_add(content_length)?;
+    if end > data.len() {
+        return None;
+    }
+    Some((&data[..end], &data[header_length..end], &data[end..]))
+}
+
+fn read(path: &Path) -> Result<Vec<u8>> {
+    std::fs::read(path)
+        .map_err(|err| Error::Other(format!("Could not read {}: {}", path.display(), err)))
+}
+
+fn invalid_pem(path: &Path) -> Error {
+    Error::Other(format!("{} is not a valid PEM file", path.display()))
+}
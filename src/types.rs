@@ -1 +1 @@
-pub type Result<T> = std::result::Result<T, std::boxed::Box<dyn std::error::Error>>;
+pub type Result<T> = std::result::Result<T, crate::error::Error>;
@@ -0,0 +1,460 @@
+//! Runs the client against a stub of the Pleasant server, which records the requests it gets
+
+use pleasent_keepass_client_rs::agent::{self, AgentClient, AgentRequest, AgentResponse};
+use pleasent_keepass_client_rs::cache_policy::CachePolicy;
+use pleasent_keepass_client_rs::docker_credential::{self, DockerCredentials};
+use pleasent_keepass_client_rs::git_credential::CredentialLookup;
+use pleasent_keepass_client_rs::{
+    Account, CacheKey, Error, OtpSource, PasswordSource, PleasantPasswordServerClient,
+};
+use serde_json::json;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use url::Url;
+
+const ROOT_ID: &str = "00000000-0000-0000-0000-000000000001";
+const DOCKER_ID: &str = "00000000-0000-0000-0000-000000000002";
+const GITLAB_ID: &str = "22222222-2222-2222-2222-222222222222";
+const REGISTRY_ID: &str = "33333333-3333-3333-3333-333333333333";
+const CREATED_ID: &str = "44444444-4444-4444-4444-444444444444";
+
+#[derive(Clone, Debug)]
+struct Request {
+    method: String,
+    path: String,
+    body: String,
+}
+
+impl Request {
+    /// The method and the path, e.g. `GET /api/v5/rest/folders`
+    fn line(&self) -> String {
+        format!("{} {}", self.method, self.path)
+    }
+}
+
+type Respond = dyn Fn(&Request) -> (u16, String) + Send + Sync;
+
+struct Stub {
+    url: Url,
+    requests: Arc<Mutex<Vec<Request>>>,
+    dir: PathBuf,
+}
+
+impl Stub {
+    /// Answers every request with `respond` until the test ends
+    async fn start<F>(name: &str, respond: F) -> Stub
+    where
+        F: Fn(&Request) -> (u16, String) + Send + Sync + 'static,
+    {
+        let dir =
+            std::env::temp_dir().join(format!("pleasant-client-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let respond: Arc<Respond> = Arc::new(respond);
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let recorded = requests.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = match listener.accept().await {
+                    Ok(connection) => connection,
+                    Err(_) => return,
+                };
+                let request = match read_request(&mut stream).await {
+                    Some(request) => request,
+                    None => continue,
+                };
+                let (status, body) = respond(&request);
+                recorded.lock().unwrap().push(request);
+                let response = format!(
+                    "HTTP/1.1 {} Stub\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+                let _ = stream.shutdown(std::net::Shutdown::Write);
+            }
+        });
+        Stub {
+            url: Url::parse(format!("http://127.0.0.1:{}/", port).as_str()).unwrap(),
+            requests,
+            dir,
+        }
+    }
+
+    fn client(&self) -> PleasantPasswordServerClient {
+        let account = Account {
+            login: "jdoe".to_string(),
+            password: PasswordSource::Value("s3cret".to_string().into()),
+            otp: OtpSource::Prompt,
+        };
+        PleasantPasswordServerClient::new(
+            self.url.clone(),
+            reqwest::Client::new(),
+            account,
+            self.dir.clone(),
+            CacheKey::KeyFile(self.dir.join("cache.key")),
+            CachePolicy::default(),
+        )
+        .unwrap()
+    }
+
+    /// The method and path of every request since the last call
+    fn take_requests(&self) -> Vec<String> {
+        self.requests
+            .lock()
+            .unwrap()
+            .drain(..)
+            .map(|request| request.line())
+            .collect()
+    }
+
+    fn bodies(&self, line: &str) -> Vec<String> {
+        self.requests
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|request| request.line() == line)
+            .map(|request| request.body.clone())
+            .collect()
+    }
+}
+
+impl Drop for Stub {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+async fn read_request(stream: &mut tokio::net::TcpStream) -> Option<Request> {
+    let mut data = Vec::new();
+    let mut buffer = [0u8; 1024];
+    let header_end = loop {
+        if let Some(index) = data.windows(4).position(|window| window == b"\r\n\r\n") {
+            break index + 4;
+        }
+        match stream.read(&mut buffer).await {
+            Ok(0) | Err(_) => return None,
+            Ok(n) => data.extend_from_slice(&buffer[..n]),
+        }
+    };
+    let head = String::from_utf8_lossy(&data[..header_end]).to_string();
+    let content_length = head
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, value)| value.trim().parse::<usize>().ok())
+        .unwrap_or(0);
+    while data.len() < header_end + content_length {
+        match stream.read(&mut buffer).await {
+            Ok(0) | Err(_) => return None,
+            Ok(n) => data.extend_from_slice(&buffer[..n]),
+        }
+    }
+    let mut request_line = head.lines().next()?.split_whitespace();
+    Some(Request {
+        method: request_line.next()?.to_string(),
+        path: request_line.next()?.to_string(),
+        body: String::from_utf8_lossy(&data[header_end..]).to_string(),
+    })
+}
+
+/// Accepts the password s3cret and every refresh token
+fn token(request: &Request) -> (u16, String) {
+    if !request.body.contains("password=s3cret") && !request.body.contains("refresh_token=") {
+        let rejected = json!({
+            "error": "invalid_grant",
+            "error_description": "The user name or password is incorrect.",
+        });
+        return (400, rejected.to_string());
+    }
+    let token = json!({
+        "access_token": "tok",
+        "expires_in": 3600,
+        "token_type": "bearer",
+        "refresh_token": "ref",
+    });
+    (200, token.to_string())
+}
+
+fn entry(id: &str, name: &str, group_id: &str) -> serde_json::Value {
+    json!({
+        "Id": id,
+        "Name": name,
+        "Username": "deploy",
+        "Notes": "",
+        "GroupId": group_id,
+        "Created": "2020-01-01T00:00:00Z",
+        "Modified": "2020-01-01T00:00:00Z",
+        "Attachments": [],
+    })
+}
+
+/// The root folder with a git host and a Docker folder with a registry. Entries created in the
+/// Docker folder are named le.com.
+fn registries(request: &Request) -> (u16, String) {
+    let entries = [
+        entry(GITLAB_ID, "gitlab.example.com", ROOT_ID),
+        entry(REGISTRY_ID, "registry.example.org", DOCKER_ID),
+        entry(CREATED_ID, "le.com", DOCKER_ID),
+    ];
+    let entry_id = request.path.strip_prefix("/api/v5/rest/Entries/");
+    match (request.method.as_str(), request.path.as_str()) {
+        ("POST", "/OAuth2/token") => token(request),
+        ("GET", "/api/v5/rest/folders") => {
+            let tree = json!({
+                "Id": ROOT_ID,
+                "ParentId": "00000000-0000-0000-0000-000000000000",
+                "Name": "Root",
+                "Created": "2020-01-01T00:00:00Z",
+                "Modified": "2020-01-01T00:00:00Z",
+                "Credentials": [entries[0]],
+                "Children": [{
+                    "Id": DOCKER_ID,
+                    "ParentId": ROOT_ID,
+                    "Name": "Docker",
+                    "Created": "2020-01-01T00:00:00Z",
+                    "Modified": "2020-01-01T00:00:00Z",
+                    "Credentials": [entries[1]],
+                }],
+            });
+            (200, tree.to_string())
+        }
+        ("POST", "/api/v5/rest/Entries") => (200, json!(CREATED_ID).to_string()),
+        ("GET", path) if path.ends_with("/password") => (200, json!("glpat-xyz").to_string()),
+        ("GET", _) => match entries
+            .iter()
+            .find(|entry| Some(entry["Id"].as_str().unwrap()) == entry_id)
+        {
+            Some(entry) => (200, entry.to_string()),
+            None => (404, "{}".to_string()),
+        },
+        ("PATCH", _) if entry_id.is_some() => (204, String::new()),
+        _ => (404, "{}".to_string()),
+    }
+}
+
+fn docker_credentials(server_url: &str) -> DockerCredentials {
+    DockerCredentials {
+        server_url: server_url.to_string(),
+        username: "ci".to_string(),
+        secret: "token".to_string(),
+    }
+}
+
+#[tokio::test]
+async fn gets_only_the_registry_named_after_the_host() {
+    let stub = Stub::start("docker-get", registries).await;
+    let client = stub.client();
+    client.sync().await.unwrap();
+
+    // `name:` matches a part of the name, le.com is in gitlab.example.com
+    for lookup in ["entry:{host}", "name:{host}"].iter() {
+        let lookup = CredentialLookup::parse(lookup).unwrap();
+        let found = docker_credential::get(&client, &lookup, "https://le.com".to_string())
+            .await
+            .unwrap();
+        assert!(found.is_none());
+    }
+
+    let lookup = CredentialLookup::parse(docker_credential::DEFAULT_LOOKUP).unwrap();
+    let credentials = docker_credential::get(&client, &lookup, "REGISTRY.example.org".to_string())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(credentials.server_url, "REGISTRY.example.org");
+    assert_eq!(credentials.username, "deploy");
+    assert_eq!(credentials.secret, "glpat-xyz");
+}
+
+#[tokio::test]
+async fn stores_a_new_entry_instead_of_updating_another_host() {
+    let stub = Stub::start("docker-store", registries).await;
+    let client = stub.client();
+    client.sync().await.unwrap();
+    stub.take_requests();
+
+    let lookup = CredentialLookup::parse("name:{host}").unwrap();
+    docker_credential::store(
+        &client,
+        &lookup,
+        Some("Root/Docker"),
+        docker_credentials("https://le.com"),
+    )
+    .await
+    .unwrap();
+    let created: serde_json::Value =
+        serde_json::from_str(stub.bodies("POST /api/v5/rest/Entries")[0].as_str()).unwrap();
+    assert_eq!(created["Name"], "le.com");
+    assert_eq!(created["GroupId"], DOCKER_ID);
+    assert_eq!(
+        stub.take_requests(),
+        vec![
+            "POST /api/v5/rest/Entries".to_string(),
+            format!("GET /api/v5/rest/Entries/{}", CREATED_ID),
+        ]
+    );
+
+    docker_credential::store(
+        &client,
+        &lookup,
+        None,
+        docker_credentials("registry.example.org"),
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        stub.take_requests(),
+        vec![
+            format!("PATCH /api/v5/rest/Entries/{}", REGISTRY_ID),
+            format!("GET /api/v5/rest/Entries/{}", REGISTRY_ID),
+        ]
+    );
+
+    let err = docker_credential::store(&client, &lookup, None, docker_credentials("other.com"))
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("PLEASANT_PASSWORD_DOCKER_FOLDER"));
+    assert!(stub.take_requests().is_empty());
+}
+
+#[tokio::test]
+async fn erases_only_the_registry_named_after_the_host() {
+    let stub = Stub::start("docker-erase", registries).await;
+    let client = stub.client();
+    client.sync().await.unwrap();
+    client.entry_password(GITLAB_ID).await.unwrap();
+    client.entry_password(REGISTRY_ID).await.unwrap();
+    let cached = |client: &PleasantPasswordServerClient| -> Vec<String> {
+        client
+            .cache_entries()
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.key)
+            .filter(|key| key == GITLAB_ID || key == REGISTRY_ID)
+            .collect()
+    };
+    assert_eq!(cached(&client).len(), 2);
+
+    let lookup = CredentialLookup::parse("name:{host}").unwrap();
+    docker_credential::erase(&client, &lookup, "https://le.com").unwrap();
+    assert_eq!(cached(&client).len(), 2);
+
+    docker_credential::erase(&client, &lookup, "https://registry.example.org/v2/").unwrap();
+    assert_eq!(cached(&client), vec![GITLAB_ID.to_string()]);
+}
+
+#[tokio::test]
+async fn unlocks_the_agent_with_the_password_of_the_login() {
+    let stub = Stub::start("agent-unlock", registries).await;
+    let client = stub.client();
+    client.sync().await.unwrap();
+    let socket = stub.dir.join(agent::SOCKET_NAME);
+
+    let requests = {
+        let socket = socket.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut agent = loop {
+                match AgentClient::connect(&socket) {
+                    Some(agent) => break agent,
+                    None => std::thread::sleep(Duration::from_millis(10)),
+                }
+            };
+            let unlock = |password: &str| AgentRequest::Unlock {
+                password: password.to_string(),
+                otp: None,
+            };
+            agent.request(&AgentRequest::Lock).unwrap();
+            assert!(matches!(
+                agent.query("registry", None),
+                Err(Error::Authentication(_))
+            ));
+            assert!(matches!(
+                agent.request(&unlock("guessed")),
+                Err(Error::Authentication(message)) if message == "The user name or password is incorrect."
+            ));
+            assert!(matches!(
+                agent.request(&unlock("s3cret")),
+                Ok(AgentResponse::Done)
+            ));
+            assert_eq!(agent.query("registry", None).unwrap().len(), 1);
+            agent.request(&AgentRequest::Stop).unwrap();
+        })
+    };
+    let (served, requested) = tokio::join!(
+        agent::serve(client, &socket, Some(Duration::from_secs(10))),
+        requests
+    );
+    requested.unwrap();
+    served.unwrap();
+    assert!(!socket.exists());
+}
+
+/// Rejects the access token at the first `rejections` password requests
+fn rejecting(rejections: usize) -> impl Fn(&Request) -> (u16, String) {
+    let rejected = AtomicUsize::new(0);
+    move |request| {
+        if request.path.ends_with("/password")
+            && rejected.fetch_add(1, Ordering::SeqCst) < rejections
+        {
+            let denied = json!({"Message": "Authorization has been denied for this request."});
+            return (401, denied.to_string());
+        }
+        registries(request)
+    }
+}
+
+#[tokio::test]
+async fn logs_in_again_once_when_the_token_is_rejected() {
+    let stub = Stub::start("rejected-once", rejecting(1)).await;
+    let client = stub.client();
+    client.sync().await.unwrap();
+    stub.take_requests();
+
+    let password = client.entry_password(REGISTRY_ID).await.unwrap();
+    assert_eq!(password.as_deref(), Some("glpat-xyz"));
+    let password_request = format!("GET /api/v5/rest/Entries/{}/password", REGISTRY_ID);
+    // the cached refresh token is used for the new login
+    let bodies = stub.bodies("POST /OAuth2/token");
+    assert_eq!(bodies.len(), 1);
+    assert!(
+        bodies[0].contains("grant_type=refresh_token"),
+        "{}",
+        bodies[0]
+    );
+    assert_eq!(
+        stub.take_requests(),
+        vec![
+            password_request.clone(),
+            "POST /OAuth2/token".to_string(),
+            password_request,
+        ]
+    );
+}
+
+#[tokio::test]
+async fn gives_up_when_the_new_token_is_rejected_too() {
+    let stub = Stub::start("rejected-always", rejecting(usize::MAX)).await;
+    let client = stub.client();
+    client.sync().await.unwrap();
+    stub.take_requests();
+
+    let err = client.entry_password(REGISTRY_ID).await.unwrap_err();
+    assert!(matches!(err, Error::Authentication(_)), "{}", err);
+    let password_request = format!("GET /api/v5/rest/Entries/{}/password", REGISTRY_ID);
+    assert_eq!(
+        stub.take_requests(),
+        vec![
+            password_request.clone(),
+            "POST /OAuth2/token".to_string(),
+            password_request,
+        ]
+    );
+}
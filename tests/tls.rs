@@ -0,0 +1,314 @@
+//! Connects to a local TLS server with certificates made up for each test
+
+use p12_keystore::{KeyStore, KeyStoreEntry, PrivateKeyChain};
+use pleasent_keepass_client_rs::tls::{self, ClientCertificate, TlsOptions, TlsVersion};
+use rcgen::{BasicConstraints, Certificate, CertificateParams, IsCa};
+use rustls::{
+    AllowAnyAuthenticatedClient, NoClientAuth, PrivateKey, ProtocolVersion, RootCertStore,
+    ServerConfig,
+};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+
+struct Pki {
+    dir: PathBuf,
+    ca: Certificate,
+    ca_der: Vec<u8>,
+    server: Certificate,
+    server_der: Vec<u8>,
+}
+
+impl Pki {
+    fn new(name: &str) -> Pki {
+        let dir =
+            std::env::temp_dir().join(format!("pleasant-tls-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let mut ca_params = CertificateParams::new(vec![]);
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = Certificate::from_params(ca_params).unwrap();
+        let ca_der = ca.serialize_der().unwrap();
+        let server = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let server_der = server.serialize_der_with_signer(&ca).unwrap();
+        let pki = Pki {
+            dir,
+            ca,
+            ca_der,
+            server,
+            server_der,
+        };
+        std::fs::write(pki.path("ca.pem"), pem("CERTIFICATE", &pki.ca_der)).unwrap();
+        pki
+    }
+
+    fn path(&self, file: &str) -> PathBuf {
+        self.dir.join(file)
+    }
+
+    fn ca_roots(&self) -> RootCertStore {
+        let mut roots = RootCertStore::empty();
+        roots
+            .add(&rustls::Certificate(self.ca_der.clone()))
+            .unwrap();
+        roots
+    }
+
+    /// A client certificate signed by the CA, written as PEM and as PKCS#12
+    fn client_certificate(&self) -> (ClientCertificate, ClientCertificate) {
+        let client = rcgen::generate_simple_self_signed(vec!["client".to_string()]).unwrap();
+        let client_der = client.serialize_der_with_signer(&self.ca).unwrap();
+        let key_der = client.serialize_private_key_der();
+
+        std::fs::write(self.path("client.pem"), pem("CERTIFICATE", &client_der)).unwrap();
+        std::fs::write(self.path("client.key"), pem("PRIVATE KEY", &key_der)).unwrap();
+
+        let mut key_store = KeyStore::new();
+        let chain = vec![
+            p12_keystore::Certificate::from_der(&client_der).unwrap(),
+            p12_keystore::Certificate::from_der(&self.ca_der).unwrap(),
+        ];
+        key_store.add_entry(
+            "client",
+            KeyStoreEntry::PrivateKeyChain(PrivateKeyChain::new(key_der, [1u8; 20], chain)),
+        );
+        std::fs::write(
+            self.path("client.p12"),
+            key_store.writer("secret").write().unwrap(),
+        )
+        .unwrap();
+
+        (
+            ClientCertificate::Pem {
+                certificate: self.path("client.pem"),
+                key: self.path("client.key"),
+            },
+            ClientCertificate::Pkcs12 {
+                file: self.path("client.p12"),
+                password: "secret".to_string(),
+            },
+        )
+    }
+
+    /// Serves `ok` to every request until the test ends. Returns the url of the server.
+    async fn serve(&self, client_auth: bool, versions: Vec<ProtocolVersion>) -> String {
+        self.serve_chain(client_auth, versions, vec![]).await
+    }
+
+    /// Like `serve`, with further certificates sent after the certificate of the server
+    async fn serve_chain(
+        &self,
+        client_auth: bool,
+        versions: Vec<ProtocolVersion>,
+        appended: Vec<Vec<u8>>,
+    ) -> String {
+        let mut config = if client_auth {
+            ServerConfig::new(AllowAnyAuthenticatedClient::new(self.ca_roots()))
+        } else {
+            ServerConfig::new(NoClientAuth::new())
+        };
+        config.versions = versions;
+        let mut chain = vec![rustls::Certificate(self.server_der.clone())];
+        chain.extend(appended.into_iter().map(rustls::Certificate));
+        config
+            .set_single_cert(chain, PrivateKey(self.server.serialize_private_key_der()))
+            .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = match listener.accept().await {
+                    Ok(connection) => connection,
+                    Err(_) => return,
+                };
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    let mut stream = match acceptor.accept(stream).await {
+                        Ok(stream) => stream,
+                        Err(_) => return,
+                    };
+                    let mut request = Vec::new();
+                    let mut buffer = [0u8; 1024];
+                    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+                        match stream.read(&mut buffer).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => request.extend_from_slice(&buffer[..n]),
+                        }
+                    }
+                    let _ = stream
+                        .write_all(
+                            b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok",
+                        )
+                        .await;
+                    let _ = stream.shutdown().await;
+                });
+            }
+        });
+        format!("https://localhost:{}/", port)
+    }
+}
+
+impl Drop for Pki {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn pem(label: &str, der: &[u8]) -> String {
+    let encoded = base64::encode(der);
+    let lines: Vec<&str> = encoded
+        .as_bytes()
+        .chunks(64)
+        .map(|line| std::str::from_utf8(line).unwrap())
+        .collect();
+    format!(
+        "-----BEGIN {}-----\n{}\n-----END {}-----\n",
+        label,
+        lines.join("\n"),
+        label
+    )
+}
+
+async fn get(url: &str, options: &TlsOptions) -> Result<String, String> {
+    let client = tls::configure(reqwest::Client::builder(), options)
+        .map_err(|err| err.to_string())?
+        .build()
+        .map_err(|err| err.to_string())?;
+    let response = client
+        .get(url)
+        .send()
+        .await
+        .map_err(|err| err.to_string())?;
+    response.text().await.map_err(|err| err.to_string())
+}
+
+fn with_ca(ca_bundle: &Path) -> TlsOptions {
+    TlsOptions {
+        ca_bundle: Some(ca_bundle.to_path_buf()),
+        ..TlsOptions::default()
+    }
+}
+
+#[tokio::test]
+async fn trusts_the_ca_bundle() {
+    let pki = Pki::new("ca");
+    let url = pki.serve(false, vec![ProtocolVersion::TLSv1_3]).await;
+
+    assert_eq!(
+        get(&url, &with_ca(&pki.path("ca.pem"))).await,
+        Ok("ok".to_string())
+    );
+
+    let untrusted = TlsOptions {
+        min_version: Some(TlsVersion::Tls12),
+        ..TlsOptions::default()
+    };
+    assert!(get(&url, &untrusted).await.is_err());
+}
+
+#[tokio::test]
+async fn rejects_a_ca_bundle_without_certificates() {
+    let pki = Pki::new("empty-ca");
+    std::fs::write(pki.path("empty.pem"), "no certificates here").unwrap();
+    let result = tls::configure(reqwest::Client::builder(), &with_ca(&pki.path("empty.pem")));
+    assert!(result.is_err());
+}
+
+#[tokio::test]
+async fn sends_the_client_certificate() {
+    let pki = Pki::new("client");
+    let url = pki.serve(true, vec![ProtocolVersion::TLSv1_3]).await;
+    let (pem_certificate, pkcs12_certificate) = pki.client_certificate();
+
+    assert!(get(&url, &with_ca(&pki.path("ca.pem"))).await.is_err());
+
+    let options = TlsOptions {
+        client_certificate: Some(pem_certificate),
+        ..with_ca(&pki.path("ca.pem"))
+    };
+    assert_eq!(get(&url, &options).await, Ok("ok".to_string()));
+
+    let options = TlsOptions {
+        client_certificate: Some(pkcs12_certificate),
+        ..with_ca(&pki.path("ca.pem"))
+    };
+    assert_eq!(get(&url, &options).await, Ok("ok".to_string()));
+}
+
+#[tokio::test]
+async fn requires_the_minimum_version() {
+    let pki = Pki::new("version");
+    let url = pki.serve(false, vec![ProtocolVersion::TLSv1_2]).await;
+
+    let options = TlsOptions {
+        min_version: Some(TlsVersion::Tls12),
+        ..with_ca(&pki.path("ca.pem"))
+    };
+    assert_eq!(get(&url, &options).await, Ok("ok".to_string()));
+
+    let options = TlsOptions {
+        min_version: Some(TlsVersion::Tls13),
+        ..with_ca(&pki.path("ca.pem"))
+    };
+    assert!(get(&url, &options).await.is_err());
+
+    assert!("1.1".parse::<TlsVersion>().is_err());
+    assert_eq!("TLSv1.3".parse::<TlsVersion>(), Ok(TlsVersion::Tls13));
+}
+
+#[tokio::test]
+async fn checks_the_pinned_keys() {
+    let pki = Pki::new("pin");
+    let url = pki.serve(false, vec![ProtocolVersion::TLSv1_3]).await;
+
+    let server_pin = format!(
+        "sha256/{}",
+        base64::encode(Sha256::digest(pki.server.get_key_pair().public_key_der()).as_slice())
+    );
+    assert_eq!(tls::spki_pin(&pki.server_der), Some(server_pin.clone()));
+
+    let options = TlsOptions {
+        pinned_keys: vec![server_pin],
+        ..with_ca(&pki.path("ca.pem"))
+    };
+    assert_eq!(get(&url, &options).await, Ok("ok".to_string()));
+
+    let other_key = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let options = TlsOptions {
+        pinned_keys: vec![tls::spki_pin(&other_key.serialize_der().unwrap()).unwrap()],
+        ..with_ca(&pki.path("ca.pem"))
+    };
+    assert!(get(&url, &options).await.is_err());
+
+    assert!(tls::parse_pin("sha256/not base64").is_err());
+}
+
+#[tokio::test]
+async fn ignores_pinned_keys_appended_to_the_chain() {
+    let pki = Pki::new("appended-pin");
+    // e.g. the public certificate of the real server, sent by an impostor with its own valid one
+    let pinned = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let pinned_der = pinned.serialize_der_with_signer(&pki.ca).unwrap();
+    let url = pki
+        .serve_chain(
+            false,
+            vec![ProtocolVersion::TLSv1_3],
+            vec![pinned_der.clone()],
+        )
+        .await;
+
+    assert_eq!(
+        get(&url, &with_ca(&pki.path("ca.pem"))).await,
+        Ok("ok".to_string())
+    );
+    let options = TlsOptions {
+        pinned_keys: vec![tls::spki_pin(&pinned_der).unwrap()],
+        ..with_ca(&pki.path("ca.pem"))
+    };
+    assert!(get(&url, &options).await.is_err());
+}